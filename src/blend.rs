@@ -0,0 +1,84 @@
+/// Data types that can be averaged, used to draw a preview of a partly collapsed wave with
+/// Wave::get_preview().
+///
+/// Implemented for numbers (rounding integers to the nearest value), and arrays and tuples of
+/// them, which covers most pixel formats.
+pub trait Blend: Sized {
+    /// Weighted average of items, weights are relative. items is never empty.
+    fn blend(items: &[(&Self, u32)]) -> Self;
+}
+
+/// Weighted average of numbers, all weights 0 counts as equal weights.
+fn average(items: impl Iterator<Item = (f64, u32)> + Clone) -> f64 {
+    let total: f64 = items.clone().map(|(_, w)| w as f64).sum();
+    if total == 0.0 {
+        let count = items.clone().count() as f64;
+        return items.map(|(v, _)| v).sum::<f64>() / count;
+    }
+    items.map(|(v, w)| v * w as f64).sum::<f64>() / total
+}
+
+macro_rules! blend_integer {
+    ($($t:ty),*) => {$(
+        impl Blend for $t {
+            fn blend(items: &[(&Self, u32)]) -> Self {
+                average(items.iter().map(|(v, w)| (**v as f64, *w))).round() as $t
+            }
+        }
+    )*};
+}
+
+blend_integer!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl Blend for f32 {
+    fn blend(items: &[(&Self, u32)]) -> Self {
+        average(items.iter().map(|(v, w)| (**v as f64, *w))) as f32
+    }
+}
+
+impl Blend for f64 {
+    fn blend(items: &[(&Self, u32)]) -> Self {
+        average(items.iter().map(|(v, w)| (**v, *w)))
+    }
+}
+
+impl<B: Blend, const K: usize> Blend for [B; K] {
+    fn blend(items: &[(&Self, u32)]) -> Self {
+        std::array::from_fn(|i| {
+            let channel: Vec<_> = items.iter().map(|(v, w)| (&v[i], *w)).collect();
+            B::blend(&channel)
+        })
+    }
+}
+
+macro_rules! blend_tuple {
+    ($($name:ident $idx:tt),*) => {
+        impl<$($name: Blend),*> Blend for ($($name,)*) {
+            fn blend(items: &[(&Self, u32)]) -> Self {
+                ($({
+                    let channel: Vec<_> = items.iter().map(|(v, w)| (&v.$idx, *w)).collect();
+                    $name::blend(&channel)
+                },)*)
+            }
+        }
+    };
+}
+
+blend_tuple!(A 0);
+blend_tuple!(A 0, B 1);
+blend_tuple!(A 0, B 1, C 2);
+blend_tuple!(A 0, B 1, C 2, D 3);
+
+#[cfg(test)]
+mod tests {
+    use super::Blend;
+
+    #[test]
+    fn blend() {
+        assert_eq!(u8::blend(&[(&0, 1), (&255, 1)]), 128);
+        assert_eq!(u8::blend(&[(&0, 3), (&100, 1)]), 25);
+        assert_eq!(f32::blend(&[(&1.0, 0), (&2.0, 0)]), 1.5);
+        assert_eq!(<[u8; 3]>::blend(&[(&[0, 10, 20], 1), (&[10, 20, 30], 1)]), [5, 15, 25]);
+        assert_eq!(<(u8, f64)>::blend(&[(&(0, 0.0), 1), (&(4, 1.0), 3)]), (3, 0.75));
+    }
+}
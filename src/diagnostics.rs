@@ -0,0 +1,138 @@
+use crate::Tile;
+
+use std::collections::HashMap;
+
+/// Problem tiles in a pallet, found by Wave::diagnose() or RuleSet::diagnose().
+///
+/// Offsets are (dx, dy) from the tile, the center (0, 0) is ignored except by .inconsistent.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Diagnostics {
+    /// (tile, dx, dy) for tiles that allow nothing at an offset. Placing one of these further than
+    /// N/2 from the edge is always a contradiction.
+    pub dead: Vec<(usize, isize, isize)>,
+    /// Tiles that no other tile allows next to them, they can only appear next to themselves.
+    pub unreachable: Vec<usize>,
+    /// Tiles that can not appear away from the edges of the output: arc consistency removes them
+    /// even on an unbounded grid. This includes dead tiles, tiles that only fit next to other
+    /// inconsistent tiles, and tiles that disallow themselves at the center.
+    pub inconsistent: Vec<usize>,
+    /// Groups of tiles with the same rules, both the tiles they allow and the tiles that allow
+    /// them. The tiles in a group are interchangeable for the solver.
+    pub equivalent: Vec<Vec<usize>>,
+}
+
+/// Analyse a pallet, see Diagnostics
+pub(crate) fn diagnose<T, const N: usize>(pallet: &[Tile<T,N>]) -> Diagnostics {
+    let size = pallet.len();
+    let center = N/2;
+    let offset = |x: usize| x as isize - center as isize;
+    let offsets: Vec<(usize, usize)> = (0..N).flat_map(|x| (0..N).map(move |y| (x, y)))
+        .filter(|(x, y)| (*x, *y) != (center, center))
+        .collect();
+
+    // allowed[tile][offset] and the reverse, allowed_by[tile][offset]: the tiles that allow tile
+    // at that offset.
+    let allowed: Vec<Vec<Vec<usize>>> = pallet.iter()
+        .map(|tile| offsets.iter().map(|(x, y)| tile.mask[*x][*y].allowed(size)).collect())
+        .collect();
+    let mut allowed_by = vec![vec![vec![]; offsets.len()]; size];
+    for tile in 0..size {
+        for o in 0..offsets.len() {
+            for other in &allowed[tile][o] {
+                allowed_by[*other][o].push(tile);
+            }
+        }
+    }
+    // Index of the opposite offset
+    let opposite = |o: usize| offsets.len() - 1 - o;
+
+    let mut diagnostics = Diagnostics::default();
+
+    for tile in 0..size {
+        for (o, (x, y)) in offsets.iter().enumerate() {
+            if allowed[tile][o].is_empty() {
+                diagnostics.dead.push((tile, offset(*x), offset(*y)));
+            }
+        }
+        if allowed_by[tile].iter().flatten().all(|other| *other == tile) {
+            diagnostics.unreachable.push(tile);
+        }
+    }
+
+    // Arc consistency with support counting: a tile stays while every offset has a remaining
+    // tile that it allows and that allows it back.
+    let mut support = vec![vec![0usize; offsets.len()]; size];
+    let mut removed = vec![false; size];
+    let mut queue = vec![];
+    for tile in 0..size {
+        for o in 0..offsets.len() {
+            support[tile][o] = allowed[tile][o].iter()
+                .filter(|other| allowed[**other][opposite(o)].binary_search(&tile).is_ok())
+                .count();
+        }
+        if support[tile].contains(&0) || !pallet[tile].mask[center][center].allows(tile) {
+            removed[tile] = true;
+            queue.push(tile);
+        }
+    }
+    while let Some(tile) = queue.pop() {
+        for o in 0..offsets.len() {
+            // tile supported every tile it allows at o that allows it back
+            for other in &allowed[tile][o] {
+                if removed[*other] || allowed[*other][opposite(o)].binary_search(&tile).is_err() {
+                    continue;
+                }
+                support[*other][opposite(o)] -= 1;
+                if support[*other][opposite(o)] == 0 {
+                    removed[*other] = true;
+                    queue.push(*other);
+                }
+            }
+        }
+    }
+    diagnostics.inconsistent = (0..size).filter(|x| removed[*x]).collect();
+
+    // Key by the tiles allowed and allowed_by at every offset
+    let mut groups: HashMap<_, Vec<usize>> = HashMap::new();
+    for tile in 0..size {
+        groups.entry((&allowed[tile][..], &allowed_by[tile][..])).or_default().push(tile);
+    }
+    diagnostics.equivalent = groups.into_values().filter(|x| x.len() > 1).collect();
+    diagnostics.equivalent.sort();
+
+    return diagnostics;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Tile;
+    use crate::RuleSet;
+
+    #[test]
+    fn diagnose() {
+        // 0 and 1 fit anywhere, 2 only fits above 3 and 3 only below 2, 4 allows anything but
+        // nothing else allows it, so it can only fill an area by itself.
+        let mut pallet: Vec<_> = (0..4).map(|_| Tile::<(), 3>::sparse(())).collect();
+        pallet.push(Tile::allow_all(5, ()));
+        for tile in 0..2 {
+            for other in 0..2 {
+                for x in 0..3 {
+                    for y in 0..3 {
+                        pallet[tile].mask[x][y].allow(other);
+                    }
+                }
+            }
+        }
+        pallet[2].mask[1][2].allow(3);
+        pallet[3].mask[1][0].allow(2);
+        pallet[2].mask[1][1].allow(2);
+        pallet[3].mask[1][1].allow(3);
+        let diagnostics = RuleSet::new(pallet).diagnose();
+        assert!(diagnostics.dead.contains(&(2, -1, 0)));
+        assert!(diagnostics.dead.contains(&(3, 1, 1)));
+        assert!(!diagnostics.dead.iter().any(|x| x.0 < 2));
+        assert_eq!(diagnostics.unreachable, vec![4]);
+        assert_eq!(diagnostics.inconsistent, vec![2, 3]);
+        assert_eq!(diagnostics.equivalent, vec![vec![0, 1]]);
+    }
+}
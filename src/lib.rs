@@ -1,5 +1,26 @@
+// The solver is written with explicit returns and index loops over the grid and masks.
+#![allow(clippy::needless_return, clippy::needless_range_loop, clippy::identity_op)]
 mod wavefunction;
+mod mask;
+mod ruleset;
+mod validate;
+mod diagnostics;
+mod trace;
+mod stats;
+mod blend;
 //mod simpletiled;
 pub mod overlapping;
+pub mod neighbourhood;
 pub use wavefunction::Wave;
 pub use wavefunction::Tile;
+pub use mask::Mask;
+pub use wavefunction::Snapshot;
+pub use wavefunction::Status;
+pub use wavefunction::CancelToken;
+pub use ruleset::RuleSet;
+pub use ruleset::Encode;
+pub use validate::RuleError;
+pub use diagnostics::Diagnostics;
+pub use trace::{Trace, Cause, Removal};
+pub use stats::{Stats, Summary};
+pub use blend::Blend;
@@ -0,0 +1,138 @@
+use std::ops::Index;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// The tiles allowed next to a tile at one offset.
+///
+/// Dense masks store a bool for every tile in the pallet, so a pallet of n tiles takes n*n*N*N
+/// bools. Sparse masks only store the ids of the allowed tiles, which is much smaller for large
+/// pallets where each tile only fits next to a few others.
+///
+/// Offsets without rules use Mask::Any, which propagation skips, so rules that only look at a
+/// few offsets (like the 4 direct neighbours) do not pay for the whole N*N square.
+///
+/// All kinds can be mixed in one pallet, and indexing returns the same as the old Vec<bool>
+/// masks: true if the tile is disallowed.
+#[derive(Debug,Clone,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Mask {
+    /// vec![bool; pallet_size], true if the tile is disallowed.
+    Dense(Vec<bool>),
+    /// Sorted ids of the allowed tiles, every other tile is disallowed.
+    Sparse(Vec<usize>),
+    /// No rule at this offset, every tile is allowed.
+    Any,
+}
+
+impl Mask {
+    /// Checks if the tile is allowed.
+    pub fn allows(&self, id: usize) -> bool {
+        match self {
+            Mask::Dense(bits) => !bits[id],
+            Mask::Sparse(ids) => ids.binary_search(&id).is_ok(),
+            Mask::Any => true,
+        }
+    }
+
+    /// Allow a tile.
+    pub fn allow(&mut self, id: usize) {
+        match self {
+            Mask::Dense(bits) => bits[id] = false,
+            Mask::Sparse(ids) => if let Err(idx) = ids.binary_search(&id) {
+                ids.insert(idx, id)
+            }
+            Mask::Any => (),
+        }
+    }
+
+    /// Disallow a tile.
+    /// Panics on Mask::Any, which does not know the pallet size, convert it to a dense or sparse
+    /// mask first.
+    pub fn disallow(&mut self, id: usize) {
+        match self {
+            Mask::Dense(bits) => bits[id] = true,
+            Mask::Sparse(ids) => if let Ok(idx) = ids.binary_search(&id) {
+                ids.remove(idx);
+            }
+            Mask::Any => panic!("can not disallow a tile in Mask::Any"),
+        }
+    }
+
+    /// Ids of all allowed tiles in a pallet of pallet_size tiles.
+    pub fn allowed(&self, pallet_size: usize) -> Vec<usize> {
+        match self {
+            Mask::Dense(bits) => (0..pallet_size).filter(|x| !bits[*x]).collect(),
+            Mask::Sparse(ids) => ids.clone(),
+            Mask::Any => (0..pallet_size).collect(),
+        }
+    }
+
+    /// Convert to a sparse mask.
+    pub fn to_sparse(&self, pallet_size: usize) -> Mask {
+        Mask::Sparse(self.allowed(pallet_size))
+    }
+
+    /// Convert to a dense mask.
+    pub fn to_dense(&self, pallet_size: usize) -> Mask {
+        Mask::Dense((0..pallet_size).map(|x| !self.allows(x)).collect())
+    }
+
+    /// Used for propagation: clears every tile this mask allows from banned.
+    /// After doing this for the masks of all possible tiles, banned is the set of tiles that are
+    /// disallowed by all of them.
+    pub(crate) fn unban(&self, banned: &mut [bool]) {
+        match self {
+            Mask::Dense(bits) => {
+                for (ban, bit) in banned.iter_mut().zip(bits) {
+                    *ban &= *bit;
+                }
+            }
+            Mask::Sparse(ids) => {
+                for id in ids {
+                    banned[*id] = false;
+                }
+            }
+            Mask::Any => banned.fill(false),
+        }
+    }
+}
+
+impl Index<usize> for Mask {
+    type Output = bool;
+    /// True if the tile is disallowed, like indexing a dense mask.
+    fn index(&self, id: usize) -> &bool {
+        if self.allows(id) {
+            &false
+        } else {
+            &true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mask;
+
+    #[test]
+    fn sparse_matches_dense() {
+        let dense = Mask::Dense(vec![true, false, true, false, false]);
+        let mut sparse = dense.to_sparse(5);
+        assert_eq!(sparse, Mask::Sparse(vec![1, 3, 4]));
+        sparse.disallow(3);
+        sparse.allow(0);
+        sparse.allow(0);
+        assert_eq!(sparse, Mask::Sparse(vec![0, 1, 4]));
+        assert_eq!(sparse.to_dense(5), Mask::Dense(vec![false, false, true, true, false]));
+        assert!(sparse[2] && !sparse[4]);
+
+        let mut banned = vec![true; 5];
+        sparse.unban(&mut banned);
+        dense.unban(&mut banned);
+        assert_eq!(banned, vec![false, false, true, false, false]);
+
+        assert_eq!(Mask::Any.to_sparse(3), Mask::Sparse(vec![0, 1, 2]));
+        assert!(!Mask::Any[2]);
+        Mask::Any.unban(&mut banned);
+        assert_eq!(banned, vec![false; 5]);
+    }
+}
@@ -0,0 +1,38 @@
+//! Offset sets for rules that do not use the whole N*N square, see Tile::neighbourhood().
+//!
+//! Offsets are (dx, dy) from the tile, and do not include the center.
+
+/// The 4 direct neighbours.
+pub fn plus() -> Vec<(isize, isize)> {
+    vec![(-1, 0), (1, 0), (0, -1), (0, 1)]
+}
+
+/// The 8 direct and diagonal neighbours.
+pub fn square() -> Vec<(isize, isize)> {
+    rect(3, 3)
+}
+
+/// Every offset in a w*h rectangle centered on the tile, w and h must be odd.
+pub fn rect(w: usize, h: usize) -> Vec<(isize, isize)> {
+    assert!(w % 2 == 1 && h % 2 == 1, "rectangle size must be odd");
+    let (rx, ry) = ((w / 2) as isize, (h / 2) as isize);
+    let mut offsets = vec![];
+    for dx in -rx..=rx {
+        for dy in -ry..=ry {
+            if (dx, dy) != (0, 0) {
+                offsets.push((dx, dy));
+            }
+        }
+    }
+    offsets
+}
+
+/// Every offset on the same row or column within distance r.
+pub fn cross(r: usize) -> Vec<(isize, isize)> {
+    let r = r as isize;
+    let mut offsets = vec![];
+    for d in 1..=r {
+        offsets.extend([(-d, 0), (d, 0), (0, -d), (0, d)]);
+    }
+    offsets
+}
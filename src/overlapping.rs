@@ -1,191 +1,529 @@
 use crate::Wave;
 use crate::Tile;
+use crate::RuleSet;
+use crate::Mask;
 
 use std::fmt::Debug;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::hash::Hash;
 
 pub type Overlapping<PixelType, const N: usize> = Wave<PixelType, N>;
 
+/// One of the 8 symmetries of a square: the 4 rotations, each with or without reflection.
+///
+/// Rotations are clockwise when x is drawn to the right and y downwards.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum Transform {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    /// Reverses the x axis
+    MirrorX,
+    /// Reverses the y axis
+    MirrorY,
+    /// Swaps x and y
+    Transpose,
+    /// Swaps x and y, and reverses both
+    AntiTranspose,
+}
+
+impl Transform {
+    /// All 8 transforms
+    pub const ALL: [Transform; 8] = [
+        Transform::Identity,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::MirrorX,
+        Transform::MirrorY,
+        Transform::Transpose,
+        Transform::AntiTranspose,
+    ];
+
+    /// Apply the transform to a square array, indexed [x][y]
+    pub fn apply<T: Clone>(&self, data: &[Vec<T>]) -> Vec<Vec<T>> {
+        let last = data.len() - 1;
+        // The location in the source of the pixel at (x, y)
+        let source = |x: usize, y: usize| match self {
+            Transform::Identity => (x, y),
+            Transform::Rotate90 => (y, last - x),
+            Transform::Rotate180 => (last - x, last - y),
+            Transform::Rotate270 => (last - y, x),
+            Transform::MirrorX => (last - x, y),
+            Transform::MirrorY => (x, last - y),
+            Transform::Transpose => (y, x),
+            Transform::AntiTranspose => (last - y, last - x),
+        };
+        return (0..data.len()).map(|x| (0..data.len()).map(|y| {
+            let (sx, sy) = source(x, y);
+            data[sx][sy].clone()
+        }).collect()).collect();
+    }
+}
+
+/// Which transformed copies of the input patterns are added to the rules.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Default)]
+pub enum Symmetry {
+    /// Only the patterns as they are in the input.
+    #[default]
+    None,
+    /// Patterns mirrored along x, y and both (the same as rotating by 180 degrees).
+    Mirror,
+    /// The 4 rotations, without reflections.
+    Rotation,
+    /// All 8 rotations and reflections.
+    Full,
+}
+
+impl Symmetry {
+    /// The transforms applied to every pattern, always starting with Transform::Identity.
+    pub fn transforms(&self) -> &'static [Transform] {
+        match self {
+            Symmetry::None => &[Transform::Identity],
+            Symmetry::Mirror => &[Transform::Identity, Transform::MirrorX, Transform::MirrorY, Transform::Rotate180],
+            Symmetry::Rotation => &Transform::ALL[..4],
+            Symmetry::Full => &Transform::ALL,
+        }
+    }
+}
+
+/// Settings for extracting the rules of the overlapping model.
+///
+/// ```
+/// use yawfc::overlapping::{Options, Symmetry};
+/// let options = Options { symmetry: Symmetry::Full, periodic_input: true, ..Default::default() };
+/// ```
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct Options {
+    /// Transformed copies of the patterns to add.
+    pub symmetry: Symmetry,
+    /// Treat the input as tileable, also sampling the patterns that wrap around its edges.
+    pub periodic_input: bool,
+    /// Force the patterns along the last row of the input (image[imagex - 1]) onto the last row
+    /// of the output, and ban them everywhere else. Used for side view samples, where this is the
+    /// ground.
+    ///
+    /// The ground row is the last row of RuleSet::reconstruct(). get_collapsed_data() only has
+    /// the center pixel of each pattern, so it only shows the ground with 2*2 patterns (N = 3),
+    /// for larger patterns its last row is the one above the ground.
+    pub ground: bool,
+    /// Print the patterns and their neighbours.
+    pub debug: bool,
+}
+
+/// A pattern learned from the input images, see Model
 #[derive(Eq,PartialEq,Hash,Clone,Debug)]
-struct Pattern<T: PartialEq + Hash + Clone + Debug> {
-    pixel_data: [[T; 3]; 3],
-    freq: usize
+pub struct Pattern<T> {
+    /// size*size pixels, indexed [x][y]. In a Model these are indexes into Model::colors, see
+    /// Model::pixels().
+    pub pixel_data: Vec<Vec<T>>,
+    /// How often the pattern was found in the input, multiplied by the sample weight. Used as the
+    /// weight of the tile.
+    pub freq: u32,
+    /// The transform that created the pattern from the input. If several did, the first one in
+    /// Symmetry::transforms().
+    pub transform: Transform,
 }
 //```
 //(0,0) (0,1) (0,2)
 //(1,0) (1,1) (0,2)
 //(2,0) (2,1) (2,2)
 //```
-impl<T: PartialEq + Hash + Clone + Debug> Pattern<T> {
-    fn extract(src: &Vec<Vec<T>>, startx: usize, starty: usize) -> Pattern<T> {
+impl<T: Clone> Pattern<T> {
+    /// Copy the pixels at startx..startx+size, starty..starty+size, wrapping around the edges of
+    /// src.
+    fn extract(src: &[Vec<T>], startx: usize, starty: usize, size: usize) -> Pattern<T> {
+        let (srcx, srcy) = (src.len(), src[0].len());
         Pattern {
-            pixel_data: [
-                [src[startx+0][starty+0].clone(),src[startx+0][starty+1].clone(),src[startx+0][starty+2].clone()],
-                [src[startx+1][starty+0].clone(),src[startx+1][starty+1].clone(),src[startx+1][starty+2].clone()],
-                [src[startx+2][starty+0].clone(),src[startx+2][starty+1].clone(),src[startx+2][starty+2].clone()]
-            ],
-            freq: 1
+            pixel_data: (startx..startx+size).map(|x| {
+                (starty..starty+size).map(|y| src[x % srcx][y % srcy].clone()).collect()
+            }).collect(),
+            freq: 1,
+            transform: Transform::Identity,
         }
     }
     
-    fn fromdata(src: [[T; 3]; 3], f:usize) -> Pattern<T> {
+    #[cfg(test)]
+    fn fromdata(src: Vec<Vec<T>>, f:u32) -> Pattern<T> {
         Pattern {
             pixel_data: src,
-            freq: f
+            freq: f,
+            transform: Transform::Identity,
         }
     }
-    fn y_mirror(&self) -> Pattern<T> {
-        let mut data = self.pixel_data.clone();
-        data.iter_mut().map(|x| x.reverse()).for_each(drop);
-        //assert!(self.pixel_data != data);
-        Pattern::fromdata(data, self.freq)
+
+    /// The pixels that overlap with another pattern placed at (dx, dy) from this one, in [x][y]
+    /// order. Two patterns can be placed at (dx, dy) if a.overlap(dx, dy) == b.overlap(-dx, -dy).
+    fn overlap(&self, dx: isize, dy: isize) -> Vec<&T> {
+        let size = self.pixel_data.len() as isize;
+        let mut pixels = vec![];
+        for x in dx.max(0)..size.min(size + dx) {
+            for y in dy.max(0)..size.min(size + dy) {
+                pixels.push(&self.pixel_data[x as usize][y as usize]);
+            }
+        }
+        pixels
     }
-    fn x_mirror(&self) -> Pattern<T> {
-        let mut data = self.pixel_data.clone();
-        data.reverse();
-        Pattern::fromdata(data, self.freq)
+
+    fn transform(&self, transform: Transform) -> Pattern<T> {
+        Pattern {
+            pixel_data: transform.apply(&self.pixel_data),
+            freq: self.freq,
+            transform,
+        }
     }
 }
 
-/// Full deduplication
-fn dedup<T: Hash+Clone+Debug+PartialEq+Eq>(array: &mut Vec<Pattern<T>>) {
-    let mut items: HashMap<Pattern<T>, usize> = HashMap::new();
-    let mut cursor = 0;
-    while array.len() > cursor {
-        match items.get(&array[cursor]) {
+/// List of unique patterns, patterns with the same pixels are merged into the first one, adding up
+/// the frequency's.
+struct Catalogue<T> {
+    /// pixels -> index in .patterns
+    index: HashMap<Vec<Vec<T>>, usize>,
+    patterns: Vec<Pattern<T>>,
+}
+
+impl<T: Hash + Eq + Clone> Catalogue<T> {
+    fn new() -> Catalogue<T> {
+        Catalogue {
+            index: HashMap::new(),
+            patterns: vec![],
+        }
+    }
+
+    fn push(&mut self, pattern: Pattern<T>) {
+        match self.index.get(&pattern.pixel_data) {
             Some(idx) => {
-                array.remove(cursor);
-                array[*idx].freq += 1;
+                let freq = &mut self.patterns[*idx].freq;
+                *freq = freq.checked_add(pattern.freq).expect("pattern frequency does not fit in a u32");
             }
             None => {
-                items.insert(array[cursor].clone(), cursor);
-                cursor += 1;
+                self.index.insert(pattern.pixel_data.clone(), self.patterns.len());
+                self.patterns.push(pattern);
             }
         }
     }
 }
 
+/// Full deduplication, see Catalogue
+fn dedup<T: Hash+Clone+Debug+PartialEq+Eq>(array: &mut Vec<Pattern<T>>) {
+    let mut catalogue = Catalogue::new();
+    for pattern in array.drain(..) {
+        catalogue.push(pattern);
+    }
+    *array = catalogue.patterns;
+}
+
 #[test]
 fn dedup_test() {
-    let mut arr = vec![0, 1, 2, 1, 3, 3, 3, 4, 5, 4,1,1,1,1,7,1];
+    let mut arr: Vec<_> = [0, 1, 2, 1, 3, 3, 3, 4, 5, 4,1,1,1,1,7,1].iter().map(|x| Pattern::fromdata(vec![vec![*x; 3]; 3], 1)).collect();
     dedup(&mut arr);
-    assert_eq!(arr, vec![0,1,2,3,4,5,7]);
+    assert_eq!(arr.iter().map(|x| x.pixel_data[0][0]).collect::<Vec<_>>(), vec![0,1,2,3,4,5,7]);
+    assert_eq!(arr.iter().map(|x| x.freq).collect::<Vec<_>>(), vec![1,7,1,3,2,1,1]);
 }
 
 /// Create a wave function collapse solver for texture generation with the overlapping model.
 ///
-/// N is hard coded at 3 (for now).
+/// The pattern size is set by the rule size N of the returned wave: patterns are (N+1)/2 pixels
+/// wide, so that overlapping patterns can be up to a pattern size minus one apart.
+///
+/// | N | pattern size |
+/// |---|--------------|
+/// | 3 | 2*2          |
+/// | 5 | 3*3          |
+/// | 7 | 4*4          |
+/// | 9 | 5*5          |
 ///
 /// The generated output has 2 constraints:
-/// - All N*N patterns in the output are present in the input. (Rotation and mirroring is  optionally allowed).
+/// - All pattern size * pattern size regions in the output are present in the input. (Rotations and mirroring are optionally allowed, see Symmetry).
 /// - The ratios of the patterns in the output should be approximately the same as the input for a large enough sample size. (This is achieved through weighting the rng.)
 ///
-/// This is achieved by extracting all N*N patterns from the image. (No boundary conditions for
-/// now, you have to pad your image.)
+/// This is achieved by extracting all pattern size * pattern size patterns from the image. By
+/// default only the patterns fully inside the image are used, set options.periodic_input for
+/// tileable images.
 ///
-/// Then computing all possible ways the patterns can overlap without conflicting. (This is blazing
-/// fast O(n^2*N^2))
+/// Then computing all possible ways the patterns can overlap without conflicting. (Patterns are
+/// grouped by the pixels they share at each offset, so this is about O(n*N^4) for n patterns,
+/// plus the size of the rules)
 ///
 /// These rules are then passed to the solver.
-pub fn overlapping<T: Debug + Hash + PartialEq + Eq + Clone>(
+///
+/// To create several outputs from the same example, use rules() once and RuleSet::wave(). To learn
+/// from several examples, use rules_from_samples(). To inspect or edit the learned patterns, use
+/// model().
+///
+/// get_collapsed_data() on the result gives the center pixel of each pattern, which crops
+/// pattern size / 2 pixels from the top and left and (pattern size - 1) / 2 from the bottom and
+/// right of the output. With options.ground and patterns larger than 2*2 that cuts off the
+/// ground, use rules() and RuleSet::reconstruct() to get the whole output.
+pub fn overlapping<T: Debug + Hash + PartialEq + Eq + Clone, const N: usize>(
     image: Vec<Vec<T>>,
     resulty: usize,
     resultx: usize,
-    mirror: bool,
-    debug: bool,
+    options: Options,
     seed: u64,
-) -> Overlapping<T,5> {
-   
-    let mut patterns: Vec<Pattern<T>> = vec![]; 
+) -> Overlapping<T,N> {
+    // Construct the wave function.
+    return rules(image, options).wave(resultx, resulty, seed);
+}
+
+/// The patterns and rules learned by the overlapping model, returned by model().
+///
+/// This can be inspected or changed before creating the rules with .rules(). Tile ids in the
+/// rules are indexes into .patterns.
+#[derive(Debug,Clone)]
+pub struct Model<T, const N: usize> {
+    /// The patterns, with their pixels as indexes into .colors
+    pub patterns: Vec<Pattern<u32>>,
+    /// Every distinct value in the inputs, in the order they were first found.
+    pub colors: Vec<T>,
+    /// [pattern][x][y] -> sorted ids of the patterns that can be placed at offset
+    /// (x - N/2, y - N/2) from the pattern without conflicting.
+    pub neighbors: Vec<[[Vec<usize>; N]; N]>,
+    /// Patterns from the last row of the inputs, see Options::ground. Empty if it is not set.
+    pub ground: Vec<usize>,
+}
+
+impl<T: Clone, const N: usize> Model<T,N> {
+    /// Create the tiles for the patterns, see rules()
+    pub fn rules(&self) -> RuleSet<T,N> {
+        let mut tile_buffer = vec![];
+
+        for (idx, pattern) in self.patterns.iter().enumerate() {
+            let size = pattern.pixel_data.len();
+            // Start by disallowing all connections, setting the addironal data to the center pixel of
+            // the pattern.
+            // Sparse masks are used because each pattern only overlaps with a few others, so dense
+            // masks would waste memory on large pallets.
+            let mut tile = Tile::sparse(self.colors[pattern.pixel_data[size/2][size/2] as usize].clone());
+
+            // The neighbor lists are sorted, and can be used as masks directly.
+            // At the center the only valid neighbor is the pattern itself, which does not remove any
+            // possibility's, so the center does not need to allow everything.
+            let neighbors = &self.neighbors[idx];
+
+            for x in 0..N {
+                for y in 0..N {
+                    tile.mask[x][y] = Mask::Sparse(neighbors[x][y].clone());
+                }
+            }
 
-    let imagex = image.len();
-    assert!(imagex >= 3);
-    let imagey = image[0].len();
-    assert!(imagey >= 3);
+            tile.weight = pattern.freq;
 
-    // For all pattern locations
-    for x in 0..(imagex-2) {
-        for y in 0..(imagey-2) {
-            // extract the patterns into the buffer
-            patterns.push(Pattern::extract(&image, x, y))
+            tile_buffer.push(tile);
         }
+
+        return RuleSet {
+            pallet: tile_buffer,
+            colors: self.colors.clone(),
+            patterns: self.patterns.iter().map(|x| x.pixel_data.clone()).collect(),
+            ground: self.ground.clone(),
+        };
     }
 
-    // deduplicate
-    dedup(&mut patterns);
+    /// The pixels of a pattern, indexed [x][y]
+    pub fn pixels(&self, pattern: usize) -> Vec<Vec<&T>> {
+        self.patterns[pattern].pixel_data.iter()
+            .map(|column| column.iter().map(|x| &self.colors[*x as usize]).collect())
+            .collect()
+    }
 
+    /// Change how likely a pattern is to be picked, the weight of its tile. Panics if freq is 0,
+    /// use .remove() to stop a pattern from appearing at all.
+    pub fn set_weight(&mut self, pattern: usize, freq: u32) {
+        assert!(freq > 0, "pattern weights must be positive");
+        self.patterns[pattern].freq = freq;
+    }
 
-    if mirror {
-        let mut buf = vec![];
-        for pattern in &patterns {
-            buf.push(pattern.y_mirror());
+    /// Remove patterns, the patterns after them move down to fill the gaps and the neighbor lists
+    /// and ground are updated to the new ids. Panics if an id is out of range.
+    ///
+    /// Removing patterns can leave others without any neighbors in some direction, check the
+    /// rules with RuleSet::diagnose().
+    pub fn remove(&mut self, ids: &[usize]) {
+        let mut removed = vec![false; self.patterns.len()];
+        for id in ids {
+            assert!(*id < self.patterns.len(), "pattern id out of range");
+            removed[*id] = true;
+        }
+        // old id -> new id, None for removed patterns
+        let mut new_ids = vec![None; self.patterns.len()];
+        let mut next = 0;
+        for id in 0..self.patterns.len() {
+            if !removed[id] {
+                new_ids[id] = Some(next);
+                next += 1;
+            }
+        }
+        // Ids keep their order, so the lists stay sorted
+        let remap = |list: &[usize]| -> Vec<usize> { list.iter().filter_map(|x| new_ids[*x]).collect() };
+
+        let mut patterns = vec![];
+        let mut neighbors = vec![];
+        for (id, pattern) in std::mem::take(&mut self.patterns).into_iter().enumerate() {
+            if !removed[id] {
+                patterns.push(pattern);
+                neighbors.push(std::array::from_fn(|x| std::array::from_fn(|y| remap(&self.neighbors[id][x][y]))));
+            }
+        }
+        self.patterns = patterns;
+        self.neighbors = neighbors;
+        self.ground = remap(&self.ground);
+    }
+
+    /// Create a solver using the rules of the model, see RuleSet::wave()
+    pub fn wave(&self, x: usize, y: usize, seed: u64) -> Overlapping<T,N> {
+        self.rules().wave(x, y, seed)
+    }
+}
+
+/// Extract the rules of the overlapping model from an example image, see overlapping().
+///
+/// The returned RuleSet includes the pixel data of every pattern.
+/// Panics if N is even or the image is smaller than a pattern (unless options.periodic_input is
+/// set).
+pub fn rules<T: Debug + Hash + PartialEq + Eq + Clone, const N: usize>(
+    image: Vec<Vec<T>>,
+    options: Options,
+) -> RuleSet<T,N> {
+    return model(image, options).rules();
+}
+
+/// Extract the rules of the overlapping model from several example images, see rules().
+///
+/// Takes (image, weight) pairs, every pattern found in an image counts weight times. A weight of
+/// None is the same as Some(1), so use None for all images to treat them equally. The images can
+/// have different sizes.
+/// Panics if a weight is Some(0), a pattern's total weight does not fit in a u32, or any of the
+/// images would make rules() panic.
+pub fn rules_from_samples<T: Debug + Hash + PartialEq + Eq + Clone, const N: usize>(
+    samples: Vec<(Vec<Vec<T>>, Option<u32>)>,
+    options: Options,
+) -> RuleSet<T,N> {
+    return model_from_samples(samples, options).rules();
+}
+
+/// Learn the patterns of an example image without creating the tiles, see Model and rules().
+pub fn model<T: Debug + Hash + PartialEq + Eq + Clone, const N: usize>(
+    image: Vec<Vec<T>>,
+    options: Options,
+) -> Model<T,N> {
+    return model_from_samples(vec![(image, None)], options);
+}
+
+/// Learn the patterns of several example images, see Model and rules_from_samples().
+pub fn model_from_samples<T: Debug + Hash + PartialEq + Eq + Clone, const N: usize>(
+    samples: Vec<(Vec<Vec<T>>, Option<u32>)>,
+    options: Options,
+) -> Model<T,N> {
+    assert!(N % 2 == 1, "N must be odd");
+    assert!(!samples.is_empty());
+    let size = N/2 + 1;
+
+    // Intern the input values, so the patterns are small integers that are cheap to hash and
+    // compare. Each distinct value is moved into the color list once, and only cloned when
+    // creating the tiles and the output.
+    let mut color_ids: HashMap<T, u32> = HashMap::new();
+    let samples: Vec<(Vec<Vec<u32>>, u32)> = samples.into_iter().map(|(image, weight)| {
+        let image = image.into_iter().map(|column| column.into_iter().map(|pixel| {
+            let next = color_ids.len() as u32;
+            *color_ids.entry(pixel).or_insert(next)
+        }).collect()).collect();
+        (image, weight.unwrap_or(1))
+    }).collect();
+    let mut colors: Vec<(u32, T)> = color_ids.into_iter().map(|(color, id)| (id, color)).collect();
+    colors.sort_by_key(|x| x.0);
+    let colors: Vec<T> = colors.into_iter().map(|x| x.1).collect();
+   
+    let mut catalogue = Catalogue::new();
+    // The patterns ending on the last row of an input
+    let mut ground_data = HashSet::new();
+
+    for (image, weight) in &samples {
+        assert!(*weight > 0, "sample weights must be positive");
+        let imagex = image.len();
+        let imagey = image[0].len();
+        // Number of pattern locations along each axis
+        let (locationsx, locationsy) = if options.periodic_input {
+            (imagex, imagey)
+        } else {
+            assert!(imagex >= size);
+            assert!(imagey >= size);
+            (imagex - size + 1, imagey - size + 1)
+        };
+
+        // For all pattern locations
+        for x in 0..locationsx {
+            for y in 0..locationsy {
+                // extract the patterns into the catalogue, merging duplicates right away so large
+                // images do not need a copy of every window.
+                let mut pattern = Pattern::extract(image, x, y, size);
+                pattern.freq = *weight;
+                catalogue.push(pattern)
+            }
+        }
+
+        if options.ground {
+            let groundx = (imagex - size % imagex) % imagex;
+            for y in 0..locationsy {
+                ground_data.insert(Pattern::extract(image, groundx, y, size).pixel_data);
+            }
         }
-        patterns.append(&mut buf);
+    }
+
+    // The catalogue also merged the patterns found in several images
+    let mut patterns = catalogue.patterns;
+
+    // Add the transformed copies, the first transform is always the identity. All patterns are
+    // added with one transform before the next, so duplicates keep the earliest transform.
+    let mut transformed = vec![];
+    for transform in options.symmetry.transforms() {
         for pattern in &patterns {
-            buf.push(pattern.x_mirror());
+            transformed.push(pattern.transform(*transform));
         }
-        patterns.append(&mut buf);
     }
-    // TODO transform
+    patterns = transformed;
 
     // Repeat deduplication because tranformations create a *lot* of duplicates.
     dedup(&mut patterns);
 
-    // Find valid ajacent patterns O(n^2)
-    // For all ids -> 3*3 array -> array of pattern idxs
-    let mut valid_neighbors: Vec<[[Vec<usize>; 5]; 5]> = vec![];
+    let ground: Vec<usize> = (0..patterns.len()).filter(|x| ground_data.contains(&patterns[*x].pixel_data)).collect();
+
+    // Find valid ajacent patterns
+    // For all ids -> N*N array -> array of pattern idxs
+    let mut valid_neighbors: Vec<[[Vec<usize>; N]; N]> = vec![];
 
     for _ in 0..patterns.len() {
-        valid_neighbors.push([
-            [vec![], vec![], vec![], vec![], vec![]],
-            [vec![], vec![], vec![], vec![], vec![]],
-            [vec![], vec![], vec![], vec![], vec![]],
-            [vec![], vec![], vec![], vec![], vec![]],
-            [vec![], vec![], vec![], vec![], vec![]],
-        ])
-    }
-
-    // For all pattern combinations
-    for a_idx in 0..patterns.len() {
-        for b_idx in 0..patterns.len() {
-            let a = &patterns[a_idx];
-            let b = &patterns[b_idx];
-            // For all possible offsets...
-            for x in 0..5 {
-                for y in 0..5 {
-                    let dx = x - (5/2 as isize);
-                    let dy = y - (5/2 as isize);
-                    let mut allowed = true;
-                    // For all pixels in pattern a...
-                    for pattern_a_x in 0..(3 as isize) {
-                        for pattern_a_y in 0..(3 as isize) {
-                            let pattern_b_x = pattern_a_x - dx;
-                            let pattern_b_y = pattern_a_y - dy;
-                            // If that pixel is in pattern b
-                            if pattern_b_x >= 0 && pattern_b_y >= 0 && pattern_b_x < 3 && pattern_b_y < 3 {
-                                // if the overlaping pixels dont match, disallow pairing
-                                if b.pixel_data[pattern_b_x as usize][pattern_b_y as usize] != a.pixel_data[pattern_a_x as usize][pattern_a_y as usize] {
-                                    allowed = false;
-                                }
-                            }
-                        }
-                    }
-                    // If it was not regected, add to parings
-                    if allowed {
-                        valid_neighbors[a_idx][x as usize][y as usize].push(b_idx);
-                    }
+        valid_neighbors.push(std::array::from_fn(|_| std::array::from_fn(|_| vec![])))
+    }
+
+    // For all possible offsets...
+    for x in 0..N {
+        for y in 0..N {
+            let dx = x as isize - (N/2) as isize;
+            let dy = y as isize - (N/2) as isize;
+            // Bucket the patterns by the pixels they would share with a pattern at (-dx, -dy),
+            // then every pattern can be placed at (dx, dy) from exactly the patterns in the bucket
+            // of its own overlap. Ids are added in order, so the buckets are sorted.
+            let mut buckets: HashMap<Vec<&u32>, Vec<usize>> = HashMap::new();
+            for (b_idx, b) in patterns.iter().enumerate() {
+                buckets.entry(b.overlap(-dx, -dy)).or_default().push(b_idx);
+            }
+            for (a_idx, a) in patterns.iter().enumerate() {
+                if let Some(bucket) = buckets.get(&a.overlap(dx, dy)) {
+                    valid_neighbors[a_idx][x][y] = bucket.clone();
                 }
             }
         }
     }
 
     // debug information
-    if debug {
+    if options.debug {
         for (n,pattern) in patterns.iter().enumerate() {
             println!("-- PATTERN -- {}", n);
             for line in &pattern.pixel_data {
-                println!("{:?}", line);
+                println!("{:?}", line.iter().map(|x| &colors[*x as usize]).collect::<Vec<_>>());
             }
         }
     
@@ -194,43 +532,400 @@ pub fn overlapping<T: Debug + Hash + PartialEq + Eq + Clone>(
         }
     }
 
-    // Construct tile data
-    let mut tile_buffer = vec![];
+    return Model {
+        patterns,
+        colors,
+        neighbors: valid_neighbors,
+        ground,
+    };
+}
+#[test]
+fn test() {
+    let img = vec![
+        vec![0, 1, 2],
+        vec![3, 4, 5],
+        vec![6, 7, 8]
+    ];
+    overlapping::<_, 5>(img, 5, 5, Options { symmetry: Symmetry::Mirror, ..Default::default() }, 123).collapse();
+}
+
+#[test]
+fn pattern_sizes() {
+    let img: Vec<Vec<u8>> = (0..6).map(|x| (0..6).map(|y| ((x / 2 + y / 2) % 2) as u8).collect()).collect();
+    let small: RuleSet<u8, 3> = rules(img.clone(), Options::default());
+    let medium: RuleSet<u8, 5> = rules(img.clone(), Options::default());
+    let large: RuleSet<u8, 9> = rules(img, Options::default());
+    assert_eq!(small.patterns[0].len(), 2);
+    assert_eq!(large.patterns[0].len(), 5);
+    // A checker board of 2*2 blocks: windows can be in 4 alignments with the blocks, each in 2
+    // colorings.
+    assert_eq!(small.pallet.len(), 8);
+    assert_eq!(medium.pallet.len(), 8);
+    assert_eq!(large.pallet.len(), 4);
+    for rules in [small.validate(), medium.validate(), large.validate()] {
+        assert_eq!(rules, Ok(()));
+    }
+    let mut wave = small.wave(8, 8, 1);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+}
+
+#[test]
+fn reuse_rules() {
+    let img = vec![
+        vec![0, 1, 2, 0],
+        vec![3, 4, 5, 3],
+        vec![6, 7, 8, 6],
+    ];
+    let rules: RuleSet<_, 5> = rules(img.clone(), Options { symmetry: Symmetry::Mirror, ..Default::default() });
+    assert_eq!(rules.patterns.len(), rules.pallet.len());
+    assert_eq!(rules.validate(), Ok(()));
+    let mut a = rules.wave(7, 4, 5);
+    a.collapse();
+    let mut b: Overlapping<_, 5> = overlapping(img, 4, 7, Options { symmetry: Symmetry::Mirror, ..Default::default() }, 5);
+    b.collapse();
+    assert_eq!(a.wave, b.wave);
+}
+
+#[test]
+fn sparse_matches_dense() {
+    let img = vec![
+        vec![0, 0, 1, 0, 0],
+        vec![0, 1, 1, 1, 0],
+        vec![1, 1, 0, 1, 1],
+        vec![0, 1, 1, 1, 0],
+        vec![0, 0, 1, 0, 0],
+    ];
+    let sparse: RuleSet<_, 5> = rules(img, Options { symmetry: Symmetry::Mirror, ..Default::default() });
+    let mut dense = sparse.clone();
+    for tile in &mut dense.pallet {
+        for mask in tile.mask.iter_mut().flatten() {
+            *mask = mask.to_dense(sparse.pallet.len());
+        }
+    }
+    let mut a = sparse.wave(12, 12, 3);
+    a.collapse();
+    let mut b = dense.wave(12, 12, 3);
+    b.collapse();
+    assert_eq!(a.wave, b.wave);
+}
+
+#[test]
+fn symmetry() {
+    let data = vec![
+        vec![0, 1],
+        vec![2, 3],
+    ];
+    // Every transform gives a different result, and rotating 4 times gets back to the start
+    let results: std::collections::HashSet<_> = Transform::ALL.iter().map(|x| x.apply(&data)).collect();
+    assert_eq!(results.len(), 8);
+    let mut rotated = data.clone();
+    for _ in 0..4 {
+        rotated = Transform::Rotate90.apply(&rotated);
+    }
+    assert_eq!(rotated, data);
+    assert_eq!(Transform::Rotate90.apply(&Transform::Rotate90.apply(&data)), Transform::Rotate180.apply(&data));
+
+    // The 4 windows of an L shape: a column, an empty square, a corner and a single pixel. Their
+    // rotations already include their reflections.
+    let img = vec![
+        vec![1, 0, 0],
+        vec![1, 0, 0],
+        vec![1, 1, 0],
+    ];
+    let none: RuleSet<_, 3> = rules(img.clone(), Options::default());
+    let rotation: RuleSet<_, 3> = rules(img.clone(), Options { symmetry: Symmetry::Rotation, ..Default::default() });
+    let full: RuleSet<_, 3> = rules(img, Options { symmetry: Symmetry::Full, ..Default::default() });
+    assert_eq!(none.pallet.len(), 4);
+    assert_eq!(rotation.pallet.len(), 1 + 4 + 4 + 4);
+    assert_eq!(full.pallet.len(), rotation.pallet.len());
+    assert_eq!(full.validate(), Ok(()));
+    let total = |rules: &RuleSet<i32, 3>| rules.pallet.iter().map(|x| x.weight).sum::<u32>();
+    assert_eq!(total(&none) * 4, total(&rotation));
+    assert_eq!(total(&none) * 8, total(&full));
+}
+
+#[test]
+fn periodic_input() {
+    // Stripes that only tile with wrapping: without it the 0 column is never next to the 2 column.
+    let img = vec![
+        vec![0, 0, 0],
+        vec![1, 1, 1],
+        vec![2, 2, 2],
+    ];
+    let bounded: RuleSet<_, 3> = rules(img.clone(), Options::default());
+    let periodic: RuleSet<_, 3> = rules(img, Options { periodic_input: true, ..Default::default() });
+    assert_eq!(bounded.patterns.len(), 2);
+    assert_eq!(periodic.colors, vec![0, 1, 2]);
+    assert_eq!(periodic.patterns.len(), 3);
+    assert!(periodic.patterns.contains(&vec![vec![2, 2], vec![0, 0]]));
+    assert_eq!(periodic.pallet.iter().map(|x| x.weight).sum::<u32>(), 9);
 
-    for (idx, pattern) in patterns.iter().enumerate() {
-        // Start by disallowing all connections, setting the addironal data to the center pixel of
-        // the pattern.
-        let mut tile = Tile::disallow_all(patterns.len(), pattern.pixel_data[1][1].clone());
+    // Wrapping also works for images smaller than a pattern
+    let tiny: RuleSet<_, 5> = rules(vec![vec![0, 1]], Options { periodic_input: true, ..Default::default() });
+    assert_eq!(tiny.patterns.len(), 2);
+    let mut wave = tiny.wave(6, 6, 0);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+}
 
-        // Allow centers
-        for i in 0..patterns.len() {
-            tile.mask[2][2][i] = false;
+#[test]
+fn ground() {
+    // A hill on flat ground, 2 is only found in the last row
+    let img = vec![
+        vec![0, 0, 0, 0, 0],
+        vec![0, 0, 1, 0, 0],
+        vec![0, 1, 1, 1, 0],
+        vec![2, 2, 2, 2, 2],
+    ];
+    let rules: RuleSet<_, 3> = rules(img, Options { ground: true, ..Default::default() });
+    assert_eq!(rules.ground.len(), 3);
+    let mut wave = rules.wave(6, 8, 2);
+    for y in 0..8 {
+        assert_eq!(wave.get_possibilities(5, y), rules.ground);
+    }
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+    let data = wave.get_collapsed_data().unwrap();
+    for x in 0..6 {
+        for y in 0..8 {
+            assert_eq!(*data[x][y] == 2, x == 5);
         }
-        
-        let neighbors = &valid_neighbors[idx];
+    }
 
-        for x in 0..5 {
-            for y in 0..5 {
-                for neighbor_idx in &neighbors[x][y] {
-                    tile.mask[x][y][*neighbor_idx] = false;
-                }
+    // With 3*3 patterns the centers stop a row above the ground, reconstruct() has it
+    let img = vec![
+        vec![0, 0, 0, 0, 0, 0, 0, 0, 0],
+        vec![0, 0, 0, 0, 0, 0, 0, 0, 0],
+        vec![0, 0, 0, 0, 0, 0, 0, 0, 0],
+        vec![0, 0, 0, 0, 1, 1, 0, 0, 0],
+        vec![0, 0, 0, 1, 1, 1, 1, 0, 0],
+        vec![2, 2, 2, 2, 2, 2, 2, 2, 2],
+    ];
+    let rules: RuleSet<_, 5> = self::rules(img, Options { ground: true, ..Default::default() });
+    let mut wave = rules.wave(5, 7, 1);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+    let data = wave.get_collapsed_data().unwrap();
+    assert!(data[4].iter().all(|x| **x != 2));
+    let (pixels, _) = rules.reconstruct(&wave).unwrap();
+    assert_eq!((pixels.len(), pixels[0].len()), (7, 9));
+    assert!(pixels[6].iter().all(|x| *x == 2));
+    assert!(pixels[..6].iter().flatten().all(|x| *x != 2));
+}
+
+#[test]
+fn multiple_samples() {
+    let a = vec![
+        vec![0, 0, 0],
+        vec![0, 1, 0],
+        vec![0, 0, 0],
+    ];
+    let b = vec![
+        vec![1, 0, 0],
+        vec![0, 0, 0],
+    ];
+    let both: RuleSet<_, 3> = rules_from_samples(vec![(a.clone(), None), (b.clone(), Some(3))], Options::default());
+    let a: RuleSet<_, 3> = rules(a, Options::default());
+    // b has one of the patterns in a, and adds an empty one, both count 3 times.
+    assert_eq!(both.patterns.len(), 5);
+    assert_eq!(both.patterns[..4], a.patterns[..]);
+    assert_eq!(both.pallet[3].weight, 1 + 3);
+    assert_eq!(both.patterns[4], vec![vec![0, 0], vec![0, 0]]);
+    assert_eq!(both.pallet[4].weight, 3);
+    assert_eq!(both.validate(), Ok(()));
+}
+
+#[test]
+fn full_output() {
+    let img = vec![
+        vec![0, 0, 1, 0, 0],
+        vec![0, 1, 1, 1, 0],
+        vec![1, 1, 0, 1, 1],
+        vec![0, 1, 1, 1, 0],
+        vec![0, 0, 1, 0, 0],
+    ];
+    let rules: RuleSet<_, 5> = rules(img, Options { periodic_input: true, ..Default::default() });
+    let mut wave = rules.wave(7, 9, 4);
+    assert!(rules.reconstruct(&wave).is_none());
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+    let (pixels, ids) = rules.reconstruct(&wave).unwrap();
+    assert_eq!(ids, wave.get_collapsed_vec().unwrap());
+    assert_eq!((pixels.len(), pixels[0].len()), (9, 11));
+    let centers = wave.get_collapsed_data().unwrap();
+    for x in 0..7 {
+        for y in 0..9 {
+            assert_eq!(pixels[x + 1][y + 1], *centers[x][y]);
+        }
+    }
+
+    // 2*2 patterns: the center is the last pixel, so nothing is cropped at the far edges
+    let img = vec![
+        vec![0, 1, 1],
+        vec![1, 0, 0],
+        vec![1, 0, 1],
+    ];
+    let small: RuleSet<_, 3> = self::rules(img, Options { periodic_input: true, ..Default::default() });
+    let mut wave = small.wave(6, 5, 2);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+    let (pixels, _) = small.reconstruct(&wave).unwrap();
+    assert_eq!((pixels.len(), pixels[0].len()), (7, 6));
+    let centers = wave.get_collapsed_data().unwrap();
+    for x in 0..6 {
+        for y in 0..5 {
+            assert_eq!(pixels[x + 1][y + 1], *centers[x][y]);
+        }
+    }
+}
+
+#[test]
+fn pattern_catalogue() {
+    let img = vec![
+        vec![1, 0, 0],
+        vec![1, 0, 0],
+        vec![1, 1, 0],
+    ];
+    let model: Model<_, 3> = model(img, Options { symmetry: Symmetry::Full, ..Default::default() });
+    let rules = model.rules();
+    assert_eq!(model.patterns.len(), rules.pallet.len());
+    assert_eq!(model.patterns[0].transform, Transform::Identity);
+    assert!(model.patterns.iter().any(|x| x.transform == Transform::Rotate90));
+    for (idx, pattern) in model.patterns.iter().enumerate() {
+        assert_eq!(pattern.pixel_data, rules.patterns[idx]);
+        assert_eq!(pattern.freq, rules.pallet[idx].weight);
+        for x in 0..3 {
+            for y in 0..3 {
+                assert_eq!(rules.pallet[idx].mask[x][y], Mask::Sparse(model.neighbors[idx][x][y].clone()));
             }
         }
+        // Every pattern fits on itself
+        assert_eq!(model.neighbors[idx][1][1], vec![idx]);
+    }
+    let mut wave = model.wave(5, 5, 0);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+}
 
-        tile.weight = pattern.freq as u32;
+#[test]
+fn edit_patterns() {
+    // Without the 2 the patterns are lines of 1s and empty space, which always fit together
+    let img = vec![
+        vec![0, 0, 0, 0],
+        vec![0, 2, 0, 0],
+        vec![1, 1, 1, 1],
+        vec![0, 0, 0, 0],
+    ];
+    let mut model: Model<_, 3> = model(img, Options { periodic_input: true, ..Default::default() });
+    let before = model.patterns.len();
+    let twos: Vec<usize> = (0..before).filter(|x| model.pixels(*x).iter().flatten().any(|x| **x == 2)).collect();
+    model.remove(&twos);
+    assert_eq!(model.patterns.len(), before - twos.len());
+    model.set_weight(0, 100);
 
-        tile_buffer.push(tile);
+    let rules = model.rules();
+    assert_eq!(rules.validate(), Ok(()));
+    assert_eq!(rules.pallet[0].weight, 100);
+    for (idx, neighbors) in model.neighbors.iter().enumerate() {
+        assert_eq!(neighbors[1][1], vec![idx]);
+        assert!(neighbors.iter().flatten().flatten().all(|x| *x < model.patterns.len()));
     }
+    let mut wave = model.wave(8, 8, 1);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+    let (pixels, _) = rules.reconstruct(&wave).unwrap();
+    assert!(!pixels.iter().flatten().any(|x| *x == 2));
+}
 
-    // Construct the wave function.
-    return Wave::new(tile_buffer, resultx, resulty, seed);
+#[test]
+#[should_panic(expected = "pattern id out of range")]
+fn remove_out_of_range() {
+    let mut model: Model<_, 3> = model(vec![vec![0, 1], vec![1, 0]], Options::default());
+    let len = model.patterns.len();
+    model.remove(&[len]);
 }
+
 #[test]
-fn test() {
+fn neighbor_search() {
+    // Compare with checking every pair of patterns pixel by pixel
+    let img: Vec<Vec<u8>> = (0..12).map(|x| (0..12).map(|y| ((x * 7 + y * 3 + x * y) % 5 % 3) as u8).collect()).collect();
+    let model: Model<_, 5> = model(img, Options { symmetry: Symmetry::Full, periodic_input: true, ..Default::default() });
+    let size = 3;
+    for (a_idx, a) in model.patterns.iter().enumerate() {
+        for x in 0..5 {
+            for y in 0..5 {
+                let (dx, dy) = (x as isize - 2, y as isize - 2);
+                let expected: Vec<usize> = (0..model.patterns.len()).filter(|b_idx| {
+                    let b = &model.patterns[*b_idx];
+                    (0..size).all(|ax| (0..size).all(|ay| {
+                        let (bx, by) = (ax as isize - dx, ay as isize - dy);
+                        if bx < 0 || by < 0 || bx >= size as isize || by >= size as isize {
+                            return true;
+                        }
+                        a.pixel_data[ax][ay] == b.pixel_data[bx as usize][by as usize]
+                    }))
+                }).collect();
+                assert_eq!(model.neighbors[a_idx][x][y], expected);
+            }
+        }
+    }
+}
+
+#[test]
+fn interned_colors() {
+    // The strings are stored once, the patterns only have their indexes
+    let img: Vec<Vec<String>> = ["ab", "ba", "ab"].iter().map(|x| x.chars().map(|x| x.to_string()).collect()).collect();
+    let model: Model<_, 3> = model(img.clone(), Options::default());
+    assert_eq!(model.colors, vec!["a", "b"]);
+    assert_eq!(model.patterns.len(), 2);
+    assert_eq!(model.patterns[0].pixel_data, vec![vec![0, 1], vec![1, 0]]);
+    assert_eq!(model.patterns[1].pixel_data, vec![vec![1, 0], vec![0, 1]]);
+    assert_eq!(model.pixels(0), vec![vec!["a", "b"], vec!["b", "a"]]);
+    assert_eq!(model.patterns[0].freq, 1);
+    let rules = model.rules();
+    assert_eq!(rules.colors, model.colors);
+    assert_eq!(rules.patterns[1], model.patterns[1].pixel_data);
+    assert_eq!(rules.pallet[0].additional, "a");
+    let mut wave = rules.wave(4, 4, 0);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+    let (pixels, _) = rules.reconstruct(&wave).unwrap();
+    assert_eq!((pixels.len(), pixels[0].len()), (5, 5));
+    assert!(pixels.iter().flatten().all(|x| x == "a" || x == "b"));
+}
+
+#[test]
+fn transform_labels() {
+    // Every window has the 1 in a different corner, so all 4 are rotations of each other, but
+    // they are all found in the input as they are.
     let img = vec![
-        vec![0, 1, 2],
-        vec![3, 4, 5],
-        vec![6, 7, 8]
+        vec![0, 0, 0],
+        vec![0, 1, 0],
+        vec![0, 0, 0],
     ];
-    let l = overlapping(img, 5, 5, true, 123).colapse();
+    let model: Model<_, 3> = model(img, Options { symmetry: Symmetry::Full, ..Default::default() });
+    assert_eq!(model.patterns.len(), 4);
+    for pattern in &model.patterns {
+        assert_eq!(pattern.transform, Transform::Identity);
+        assert_eq!(pattern.freq, 8);
+    }
+}
+
+#[test]
+fn large_sample_weights() {
+    // Two patterns with a weight of 4e9 each, more than a u32 together
+    let img: Vec<Vec<u8>> = (0..3).map(|x| (0..3).map(|y| ((x + y) % 2) as u8).collect()).collect();
+    let model: Model<_, 3> = model_from_samples(vec![(img, Some(2_000_000_000))], Options::default());
+    assert_eq!(model.patterns.iter().map(|x| x.freq).collect::<Vec<_>>(), vec![4_000_000_000; 2]);
+    let mut wave = model.wave(6, 6, 0);
+    wave.collapse();
+    assert!(!wave.is_contradiction());
+}
+
+#[test]
+#[should_panic(expected = "does not fit in a u32")]
+fn weight_overflow() {
+    let img = vec![vec![0, 0], vec![0, 0]];
+    let _: Model<_, 3> = model_from_samples(vec![(img.clone(), Some(u32::MAX)), (img, Some(1))], Options::default());
 }
@@ -0,0 +1,444 @@
+use crate::Wave;
+use crate::Tile;
+use crate::Mask;
+use crate::RuleError;
+use crate::validate::{validate, validate_sizes};
+use crate::Diagnostics;
+use crate::diagnostics::diagnose;
+
+use rand::RngCore;
+use std::io;
+use std::io::{Read, Write};
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// A pallet of tiles and the data used to build it, independent of output size and seed.
+///
+/// Building the rules can be expensive (see overlapping::rules()), a RuleSet lets that be done
+/// once and reused to create any number of Waves, or be saved to a file with .save() and loaded
+/// again with RuleSet::load().
+#[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RuleSet<T, const N: usize> {
+    /// The tiles and their rules, as passed to Wave::new()
+    pub pallet: Vec<Tile<T,N>>,
+    /// The distinct pixel values of the patterns, .patterns stores indexes into this.
+    pub colors: Vec<T>,
+    /// The pixel data of the pattern each tile was extracted from as indexes into .colors,
+    /// indexed [tile][x][y]. Empty for pallets that were not learned from an example.
+    pub patterns: Vec<Vec<Vec<u32>>>,
+    /// Tiles forced along the last row of the output (x = wave.x - 1) and banned everywhere else,
+    /// see overlapping::Options::ground. Empty for no constraint.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub ground: Vec<usize>,
+}
+
+/// Pixels and tile ids, indexed [x][y], returned by RuleSet::reconstruct()
+type Reconstruction<T> = (Vec<Vec<T>>, Vec<Vec<usize>>);
+
+/// File format version written by RuleSet::save()
+const VERSION: u16 = 1;
+const MAGIC: &[u8; 4] = b"YWFC";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut bytes = vec![0u8; bits.len().div_ceil(8)];
+    for (idx, bit) in bits.iter().enumerate() {
+        if *bit {
+            bytes[idx / 8] |= 1 << (idx % 8);
+        }
+    }
+    bytes
+}
+
+fn unpack_bits(bytes: &[u8], len: usize) -> Vec<bool> {
+    (0..len).map(|idx| bytes[idx / 8] & (1 << (idx % 8)) != 0).collect()
+}
+
+fn read_bytes<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl<T, const N: usize> RuleSet<T, N> {
+    /// Create a rule set from a hand made pallet, without pattern data.
+    pub fn new(pallet: Vec<Tile<T,N>>) -> RuleSet<T,N> {
+        RuleSet {
+            pallet,
+            colors: vec![],
+            patterns: vec![],
+            ground: vec![],
+        }
+    }
+
+    /// Check the rules for mistakes, see Wave::validate()
+    pub fn validate(&self) -> Result<(), Vec<RuleError>> {
+        let errors = validate(&self.pallet, self.pallet.len());
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Find tiles that cause contradictions or are redundant, see Diagnostics
+    pub fn diagnose(&self) -> Diagnostics {
+        diagnose(&self.pallet)
+    }
+
+    /// Create a solver using these rules, see Wave::new()
+    pub fn wave(&self, x: usize, y: usize, seed: u64) -> Wave<T,N> where T: Clone {
+        let mut wave = Wave::new(self.pallet.clone(), x, y, seed);
+        self.apply_ground(&mut wave);
+        wave
+    }
+
+    /// Create a solver using these rules and a specific rng, see Wave::with_rng()
+    pub fn wave_with_rng<R: RngCore>(&self, x: usize, y: usize, rng: R) -> Wave<T,N,R> where T: Clone {
+        let mut wave = Wave::with_rng(self.pallet.clone(), x, y, rng);
+        self.apply_ground(&mut wave);
+        wave
+    }
+
+    /// Restrict the last row of a new wave to the ground tiles, and every other row to the rest.
+    fn apply_ground<R: RngCore>(&self, wave: &mut Wave<T,N,R>) where T: Clone {
+        if self.ground.is_empty() {
+            return;
+        }
+        let other: Vec<usize> = (0..self.pallet.len()).filter(|x| !self.ground.contains(x)).collect();
+        for x in 0..wave.x {
+            let allowed = if x == wave.x - 1 { &self.ground } else { &other };
+            for y in 0..wave.y {
+                wave.restrict(x, y, allowed);
+            }
+        }
+    }
+
+    /// Build the full output of a collapsed wave from the patterns of its tiles, returns the pixels
+    /// and the tile id at every location.
+    ///
+    /// Each location contributes the first pixel of its pattern, and the locations along the last
+    /// row and column their whole pattern, so the image is pattern size - 1 pixels larger than the
+    /// wave on each axis. The tile data of the overlapping model is the pixel at
+    /// [size/2][size/2] of the pattern, so .get_collapsed_data() is the same image with size/2
+    /// pixels cropped from the start of each axis and size - 1 - size/2 from the end. For odd
+    /// sizes that is size/2 from each side, for even sizes one pixel less at the end.
+    ///
+    /// Returns None if the wave is not fully collapsed or the rule set has no patterns.
+    pub fn reconstruct<R: RngCore>(&self, wave: &Wave<T,N,R>) -> Option<Reconstruction<T>> where T: Clone {
+        let size = self.patterns.first()?.len();
+        let ids = wave.get_collapsed_vec()?;
+        let mut pixels = vec![];
+        for x in 0..wave.x + size - 1 {
+            let tilex = x.min(wave.x - 1);
+            let mut column = vec![];
+            for y in 0..wave.y + size - 1 {
+                let tiley = y.min(wave.y - 1);
+                let color = self.patterns[ids[tilex][tiley]][x - tilex][y - tiley];
+                column.push(self.colors[color as usize].clone());
+            }
+            pixels.push(column);
+        }
+        return Some((pixels, ids));
+    }
+
+    /// Write the rule set in a compact binary format.
+    ///
+    /// Layout (all integers little endian):
+    /// - "YWFC", u16 version, u16 N, u32 pallet size, u32 pattern size (0 if there are no patterns)
+    /// - per tile: u32 weight, additional data, then the N*N masks in [x][y] order. Each mask is a
+    ///   u8 kind, followed by one bit per tile padded to a whole byte for dense masks (kind 0), a
+    ///   u32 count and u32 ids for sparse masks (kind 1), or nothing for Mask::Any (kind 2).
+    /// - u32 number of colors, then the colors.
+    /// - per tile if there are patterns: pattern size * pattern size u32 color indexes in [x][y]
+    ///   order.
+    /// - u32 number of ground tiles, then their u32 ids.
+    ///
+    /// Fails with ErrorKind::InvalidInput without writing anything if the rules could not be
+    /// loaded again: masks that do not fit the pallet (see .validate()), patterns that are not all
+    /// the same square size or not one per tile, colors outside .colors or ground tiles outside
+    /// the pallet.
+    pub fn save<W: Write>(&self, mut w: W) -> io::Result<()> where T: Encode {
+        let pattern_size = self.patterns.first().map(|x| x.len()).unwrap_or(0);
+        if !validate_sizes(&self.pallet, self.pallet.len()).is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "masks do not fit the pallet"));
+        }
+        if !self.patterns.is_empty() && self.patterns.len() != self.pallet.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not one pattern per tile"));
+        }
+        if self.patterns.iter().flatten().any(|x| x.len() != pattern_size) || self.patterns.iter().any(|x| x.len() != pattern_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "patterns are not all the same square size"));
+        }
+        if self.patterns.iter().flatten().flatten().any(|x| *x as usize >= self.colors.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "pattern color outside the colors"));
+        }
+        if self.ground.iter().any(|x| *x >= self.pallet.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "ground tile outside the pallet"));
+        }
+        w.write_all(MAGIC)?;
+        VERSION.encode(&mut w)?;
+        (N as u16).encode(&mut w)?;
+        (self.pallet.len() as u32).encode(&mut w)?;
+        (pattern_size as u32).encode(&mut w)?;
+        for tile in &self.pallet {
+            tile.weight.encode(&mut w)?;
+            tile.additional.encode(&mut w)?;
+            for mask in tile.mask.iter().flatten() {
+                match mask {
+                    Mask::Dense(bits) => {
+                        0u8.encode(&mut w)?;
+                        w.write_all(&pack_bits(bits))?;
+                    }
+                    Mask::Sparse(ids) => {
+                        1u8.encode(&mut w)?;
+                        (ids.len() as u32).encode(&mut w)?;
+                        for id in ids {
+                            (*id as u32).encode(&mut w)?;
+                        }
+                    }
+                    Mask::Any => 2u8.encode(&mut w)?,
+                }
+            }
+        }
+        (self.colors.len() as u32).encode(&mut w)?;
+        for color in &self.colors {
+            color.encode(&mut w)?;
+        }
+        for pattern in &self.patterns {
+            for column in pattern {
+                for pixel in column {
+                    pixel.encode(&mut w)?;
+                }
+            }
+        }
+        (self.ground.len() as u32).encode(&mut w)?;
+        for id in &self.ground {
+            (*id as u32).encode(&mut w)?;
+        }
+        Ok(())
+    }
+
+    /// Read a rule set written by .save()
+    /// Fails with ErrorKind::InvalidData if the file is not a rule set, has an unknown version or
+    /// a different N.
+    pub fn load<R: Read>(mut r: R) -> io::Result<RuleSet<T,N>> where T: Encode {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a rule set file"));
+        }
+        let version = u16::decode(&mut r)?;
+        if version != VERSION {
+            return Err(invalid("unsupported rule set version"));
+        }
+        if u16::decode(&mut r)? as usize != N {
+            return Err(invalid("rule set has a different N"));
+        }
+        let pallet_size = u32::decode(&mut r)? as usize;
+        let pattern_size = u32::decode(&mut r)? as usize;
+
+        let mut pallet = vec![];
+        for _ in 0..pallet_size {
+            let weight = u32::decode(&mut r)?;
+            let additional = T::decode(&mut r)?;
+            let mut tile = Tile::sparse(additional);
+            tile.weight = weight;
+            for mask in tile.mask.iter_mut().flatten() {
+                *mask = match u8::decode(&mut r)? {
+                    0 => Mask::Dense(unpack_bits(&read_bytes(&mut r, pallet_size.div_ceil(8))?, pallet_size)),
+                    1 => {
+                        let count = u32::decode(&mut r)?;
+                        let ids: Vec<usize> = (0..count).map(|_| Ok(u32::decode(&mut r)? as usize)).collect::<io::Result<_>>()?;
+                        if ids.iter().any(|x| *x >= pallet_size) || !ids.windows(2).all(|x| x[0] < x[1]) {
+                            return Err(invalid("bad sparse mask"));
+                        }
+                        Mask::Sparse(ids)
+                    }
+                    2 => Mask::Any,
+                    _ => return Err(invalid("unknown mask kind")),
+                }
+            }
+            pallet.push(tile);
+        }
+
+        let count = u32::decode(&mut r)?;
+        let colors = (0..count).map(|_| T::decode(&mut r)).collect::<io::Result<Vec<_>>>()?;
+        let mut patterns = vec![];
+        if pattern_size > 0 {
+            for _ in 0..pallet_size {
+                let mut pattern = vec![];
+                for _ in 0..pattern_size {
+                    let column: Vec<u32> = (0..pattern_size).map(|_| u32::decode(&mut r)).collect::<io::Result<_>>()?;
+                    if column.iter().any(|x| *x as usize >= colors.len()) {
+                        return Err(invalid("bad pattern color"));
+                    }
+                    pattern.push(column);
+                }
+                patterns.push(pattern);
+            }
+        }
+
+        let mut ground = vec![];
+        let count = u32::decode(&mut r)?;
+        for _ in 0..count {
+            let id = u32::decode(&mut r)? as usize;
+            if id >= pallet_size {
+                return Err(invalid("bad ground tile"));
+            }
+            ground.push(id);
+        }
+
+        Ok(RuleSet { pallet, colors, patterns, ground })
+    }
+}
+
+/// Binary encoding for tile data, used by RuleSet::save() and RuleSet::load().
+/// Implemented for primitive types, arrays, tuples and Vecs of them.
+pub trait Encode: Sized {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()>;
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! encode_number {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+            fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+                let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                r.read_exact(&mut bytes)?;
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+encode_number!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Encode for bool {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u8).encode(w)
+    }
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(u8::decode(r)? != 0)
+    }
+}
+
+impl Encode for char {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (*self as u32).encode(w)
+    }
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        char::from_u32(u32::decode(r)?).ok_or_else(|| invalid("invalid char"))
+    }
+}
+
+impl Encode for () {
+    fn encode<W: Write>(&self, _w: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+    fn decode<R: Read>(_r: &mut R) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+impl<E: Encode, const K: usize> Encode for [E; K] {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.iter().try_for_each(|x| x.encode(w))
+    }
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let items = (0..K).map(|_| E::decode(r)).collect::<io::Result<Vec<_>>>()?;
+        Ok(items.try_into().ok().unwrap())
+    }
+}
+
+impl<E: Encode> Encode for Vec<E> {
+    fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        (self.len() as u32).encode(w)?;
+        self.iter().try_for_each(|x| x.encode(w))
+    }
+    fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+        let len = u32::decode(r)?;
+        (0..len).map(|_| E::decode(r)).collect()
+    }
+}
+
+macro_rules! encode_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: Encode),*> Encode for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode<W: Write>(&self, w: &mut W) -> io::Result<()> {
+                let ($($name,)*) = self;
+                $($name.encode(w)?;)*
+                Ok(())
+            }
+            fn decode<R: Read>(r: &mut R) -> io::Result<Self> {
+                Ok(($($name::decode(r)?,)*))
+            }
+        }
+    };
+}
+
+encode_tuple!(A);
+encode_tuple!(A, B);
+encode_tuple!(A, B, C);
+encode_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::RuleSet;
+    use crate::Tile;
+
+    #[test]
+    fn save_load() {
+        let mut pallet = vec![Tile::<[u8; 3], 3>::allow_all(3, [1, 2, 3]), Tile::allow_all(3, [4, 5, 6]), Tile::allow_all(3, [7, 8, 9])];
+        pallet[0].disallow(2);
+        pallet[1].weight = 12;
+        pallet[2].to_sparse(3);
+        pallet[2].mask[0][1].disallow(1);
+        pallet[2].mask[2][2] = crate::Mask::Any;
+        let mut rules = RuleSet::new(pallet);
+        rules.colors = vec![[0; 3], [1; 3], [2; 3]];
+        rules.patterns = vec![vec![vec![0; 2]; 2], vec![vec![1, 2]; 2], vec![vec![2; 2]; 2]];
+        rules.ground = vec![1];
+
+        let mut file = vec![];
+        rules.save(&mut file).unwrap();
+        let loaded: RuleSet<[u8; 3], 3> = RuleSet::load(file.as_slice()).unwrap();
+        assert_eq!(loaded.colors, rules.colors);
+        assert_eq!(loaded.patterns, rules.patterns);
+        assert_eq!(loaded.ground, rules.ground);
+        for (a, b) in loaded.pallet.iter().zip(&rules.pallet) {
+            assert_eq!(a.additional, b.additional);
+            assert_eq!(a.weight, b.weight);
+            assert_eq!(a.mask, b.mask);
+        }
+
+        assert!(RuleSet::<[u8; 3], 5>::load(file.as_slice()).is_err());
+        assert!(RuleSet::<[u8; 3], 3>::load(&file[1..]).is_err());
+
+        // Rules that would not load again are not written
+        let invalid_input = |rules: &RuleSet<[u8; 3], 3>| {
+            let mut file = vec![];
+            let error = rules.save(&mut file).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(file.is_empty());
+        };
+        let mut bad = rules.clone();
+        bad.pallet[0].mask[0][0] = crate::Mask::Dense(vec![false; 9]);
+        invalid_input(&bad);
+        let mut bad = rules.clone();
+        bad.patterns[1][0].pop();
+        invalid_input(&bad);
+        let mut bad = rules.clone();
+        bad.patterns.pop();
+        invalid_input(&bad);
+        let mut bad = rules.clone();
+        bad.patterns[2][1][0] = 3;
+        invalid_input(&bad);
+        let mut bad = rules.clone();
+        bad.ground.push(3);
+        invalid_input(&bad);
+    }
+}
@@ -0,0 +1,36 @@
+use std::time::Duration;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// Counters updated by a Wave while collapsing, see Wave::stats
+///
+/// These count the work done, so they are not changed by Wave::restore().
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Stats {
+    /// Number of observations (calls to .step())
+    pub steps: usize,
+    /// Number of locations propagation applied the rules of
+    pub pops: usize,
+    /// Number of possibility's removed by propagation
+    pub bans: usize,
+    /// Time spent finding the lowest entropy location and choosing a tile
+    pub selection_time: Duration,
+    /// Time spent propagating
+    pub propagation_time: Duration,
+}
+
+/// The state and statistics of a Wave, returned by Wave::summary()
+#[derive(Debug,Clone,PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Summary {
+    /// Total number of locations
+    pub cells: usize,
+    /// Locations with a single possibility left
+    pub collapsed: usize,
+    /// Locations with no possibility's left
+    pub contradictions: usize,
+    /// Sum of the Shannon entropy (in nats, using the tile weights) of all locations
+    pub entropy: f64,
+    pub stats: Stats,
+}
@@ -0,0 +1,138 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// Why a tile was removed from a location.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Cause {
+    /// The location was collapsed to another tile by an observation (Wave::step()), step counts
+    /// the observations since tracing was enabled, starting at 0.
+    Observed { step: usize },
+    /// Propagating from the location `from` removed the tile, because every tile still possible
+    /// there disallows it at offset (dx, dy).
+    Banned { from: (usize, usize), dx: isize, dy: isize },
+    /// Removed by Wave::restrict() before collapsing.
+    Restricted,
+}
+
+/// A single removed possibility, as returned by Trace::explain()
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Removal {
+    pub x: usize,
+    pub y: usize,
+    pub tile: usize,
+    pub cause: Cause,
+    /// Position in the order removals happened, starting at 0.
+    pub order: usize,
+}
+
+/// Order and cause of a removal
+type Entry = (usize, Cause);
+
+/// Records the cause of every removed possibility while collapsing, enabled with
+/// Wave::enable_trace().
+///
+/// This stores an entry for every tile at every location, so it uses a lot of memory on large
+/// waves.
+#[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Trace {
+    /// [x][y][tile] -> order and cause of the removal, None if the tile is still possible or was
+    /// removed before tracing was enabled.
+    removed: Vec<Vec<Vec<Option<Entry>>>>,
+    removals: usize,
+    steps: usize,
+}
+
+impl Trace {
+    pub(crate) fn new(x: usize, y: usize, pallet_size: usize) -> Trace {
+        Trace {
+            removed: vec![vec![vec![None; pallet_size]; y]; x],
+            removals: 0,
+            steps: 0,
+        }
+    }
+
+    /// Record an observation collapsing superposition to a single tile.
+    pub(crate) fn observe(&mut self, x: usize, y: usize, superposition: &[bool], selection: usize) {
+        let cause = Cause::Observed { step: self.steps };
+        for (tile, bit) in superposition.iter().enumerate() {
+            if *bit && tile != selection {
+                self.remove(x, y, tile, cause);
+            }
+        }
+        self.steps += 1;
+    }
+
+    /// Record the removal of a single tile.
+    pub(crate) fn remove(&mut self, x: usize, y: usize, tile: usize, cause: Cause) {
+        self.removed[x][y][tile] = Some((self.removals, cause));
+        self.removals += 1;
+    }
+
+    /// Why the tile was removed from a location, None if it is still possible or the removal was
+    /// not recorded.
+    pub fn cause(&self, x: usize, y: usize, tile: usize) -> Option<Cause> {
+        self.removed[x][y][tile].map(|x| x.1)
+    }
+
+    /// All removals at a location, in the order they happened.
+    pub fn removals(&self, x: usize, y: usize) -> Vec<Removal> {
+        let mut removals: Vec<_> = self.removed[x][y].iter().enumerate()
+            .filter_map(|(tile, removed)| removed.map(|(order, cause)| Removal { x, y, tile, cause, order }))
+            .collect();
+        removals.sort_by_key(|x| x.order);
+        removals
+    }
+
+    /// The chain of observations and bans that removed possibility's from a location, in the
+    /// order they happened.
+    ///
+    /// This includes the removals at the location, and for every ban, the earlier removals at the
+    /// location it was propagated from.
+    pub fn explain(&self, x: usize, y: usize) -> Vec<Removal> {
+        // Highest order already explained for each location
+        let mut explained: Vec<Vec<Option<usize>>> = vec![vec![None; self.removed[0].len()]; self.removed.len()];
+        let mut events = vec![];
+        let mut stack = vec![(x, y, usize::MAX)];
+        while let Some((x, y, before)) = stack.pop() {
+            let done = explained[x][y];
+            if done.is_some_and(|done| done >= before) {
+                continue;
+            }
+            explained[x][y] = Some(before);
+            for removal in self.removals(x, y) {
+                if removal.order >= before || done.is_some_and(|done| removal.order < done) {
+                    continue;
+                }
+                if let Cause::Banned { from, .. } = removal.cause {
+                    stack.push((from.0, from.1, removal.order));
+                }
+                events.push(removal);
+            }
+        }
+        events.sort_by_key(|x| x.order);
+        events
+    }
+
+    /// Find the first location to run out of possibility's, None if no location recorded in the
+    /// trace has.
+    pub fn first_contradiction(&self, wave: &[Vec<Vec<bool>>]) -> Option<(usize, usize)> {
+        let mut first = None;
+        for x in 0..wave.len() {
+            for y in 0..wave[x].len() {
+                if wave[x][y].iter().any(|x| *x) {
+                    continue;
+                }
+                let emptied = self.removed[x][y].iter().flatten().map(|x| x.0).max();
+                if let Some(emptied) = emptied {
+                    if first.is_none_or(|(_, _, order)| emptied < order) {
+                        first = Some((x, y, emptied));
+                    }
+                }
+            }
+        }
+        first.map(|(x, y, _)| (x, y))
+    }
+}
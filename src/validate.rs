@@ -0,0 +1,120 @@
+use crate::Tile;
+use crate::Mask;
+
+use std::fmt;
+
+/// A problem with a pallet found by Wave::validate() or RuleSet::validate()
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum RuleError {
+    /// N is even, so the masks have no center.
+    EvenSize(usize),
+    /// Wave::pallet_size does not match the length of the pallet.
+    PalletSize { pallet_size: usize, len: usize },
+    /// A mask does not fit the pallet: a dense mask of the wrong length, or a sparse mask with
+    /// unsorted, duplicate or out of range ids.
+    MaskSize { tile: usize, dx: isize, dy: isize },
+    /// tile allows other at offset (dx, dy), but other does not allow tile at (-dx, -dy).
+    /// Rules like this only get applied when propagating from one side.
+    Asymmetric { tile: usize, other: usize, dx: isize, dy: isize },
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleError::EvenSize(n) => write!(f, "rule size {} is not odd", n),
+            RuleError::PalletSize { pallet_size, len } => write!(f, "pallet_size is {} but the pallet has {} tiles", pallet_size, len),
+            RuleError::MaskSize { tile, dx, dy } => write!(f, "mask of tile {} at ({}, {}) does not match the pallet size", tile, dx, dy),
+            RuleError::Asymmetric { tile, other, dx, dy } => write!(f, "tile {} allows {} at ({}, {}), but {} does not allow {} at ({}, {})", tile, other, dx, dy, other, tile, -dx, -dy),
+        }
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+fn mask_fits(mask: &Mask, pallet_size: usize) -> bool {
+    match mask {
+        Mask::Dense(bits) => bits.len() == pallet_size,
+        Mask::Sparse(ids) => ids.iter().all(|x| *x < pallet_size) && ids.windows(2).all(|x| x[0] < x[1]),
+        Mask::Any => true,
+    }
+}
+
+/// Check N, the pallet size and the size of every mask, the checks validate() does before the
+/// symmetry check.
+pub(crate) fn validate_sizes<T, const N: usize>(pallet: &[Tile<T,N>], pallet_size: usize) -> Vec<RuleError> {
+    let mut errors = vec![];
+    let offset = |x: usize| x as isize - (N/2) as isize;
+    if N % 2 == 0 {
+        errors.push(RuleError::EvenSize(N));
+    }
+    if pallet_size != pallet.len() {
+        errors.push(RuleError::PalletSize { pallet_size, len: pallet.len() });
+    }
+    for (tile, rules) in pallet.iter().enumerate() {
+        for x in 0..N {
+            for y in 0..N {
+                if !mask_fits(&rules.mask[x][y], pallet.len()) {
+                    errors.push(RuleError::MaskSize { tile, dx: offset(x), dy: offset(y) });
+                }
+            }
+        }
+    }
+    return errors;
+}
+
+/// Check a pallet for mistakes, returns all problems found.
+/// The symmetry check is skipped if there are any badly sized masks.
+pub(crate) fn validate<T, const N: usize>(pallet: &[Tile<T,N>], pallet_size: usize) -> Vec<RuleError> {
+    let mut errors = validate_sizes(pallet, pallet_size);
+    let offset = |x: usize| x as isize - (N/2) as isize;
+    if !errors.is_empty() {
+        return errors;
+    }
+
+    for (tile, rules) in pallet.iter().enumerate() {
+        for x in 0..N {
+            for y in 0..N {
+                for other in rules.mask[x][y].allowed(pallet.len()) {
+                    if !pallet[other].mask[N-1-x][N-1-y].allows(tile) {
+                        errors.push(RuleError::Asymmetric { tile, other, dx: offset(x), dy: offset(y) });
+                    }
+                }
+            }
+        }
+    }
+    return errors;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RuleError;
+    use crate::Tile;
+    use crate::Wave;
+
+    #[test]
+    fn asymmetric() {
+        let mut pallet = vec![Tile::<(), 3>::allow_all(2, ()), Tile::allow_all(2, ())];
+        pallet[0].mask[2][1].disallow(1);
+        let mut wave = Wave::new(pallet, 3, 3, 0);
+        assert_eq!(wave.validate(), Err(vec![RuleError::Asymmetric { tile: 1, other: 0, dx: -1, dy: 0 }]));
+        wave.pallet[1].mask[0][1].disallow(0);
+        assert_eq!(wave.validate(), Ok(()));
+
+        let mut pallet = vec![Tile::<(), 5>::allow_all(2, ()), Tile::allow_all(2, ())];
+        pallet[0].disallow_direct(1);
+        pallet[1].disallow_direct(0);
+        assert_eq!(Wave::new(pallet, 3, 3, 0).validate(), Ok(()));
+    }
+
+    #[test]
+    fn bad_sizes() {
+        let mut pallet = vec![Tile::<(), 3>::allow_all(2, ()), Tile::sparse(())];
+        pallet[1].mask[0][0] = crate::Mask::Sparse(vec![1, 0]);
+        let mut wave = Wave::new(pallet, 3, 3, 0);
+        wave.pallet_size = 3;
+        assert_eq!(wave.validate(), Err(vec![
+            RuleError::PalletSize { pallet_size: 3, len: 2 },
+            RuleError::MaskSize { tile: 1, dx: -1, dy: -1 },
+        ]));
+    }
+}
@@ -1,58 +1,148 @@
 use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+use crate::Mask;
+use crate::RuleError;
+use crate::validate::validate;
+use crate::Diagnostics;
+use crate::diagnostics::diagnose;
+use crate::{Trace, Cause, Removal};
+use crate::{Stats, Summary};
+use crate::Blend;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
 
 /// Stucture for holding the maching rules and assocatied data for a tile type.
 /// T: assocatied data tye
 /// N: the pattern size. MUST BE ODD
 #[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tile<T, const N: usize> {
     /// Additional data type, such as pixel value for image generation.
     pub additional: T,
     /// The freqency the tile should appear at, as a fraction of the sum of the weights of all
     /// tiles.
     pub weight: u32,
-    /// A table of allowable ageccent tyles
+    /// A table of allowable ageccent tyles, indexed [x][y] with the tile itself at the center.
+    /// Indexing a mask by tile id gives:
     /// 0 : allowed
     /// 1 : disllowed
-    pub mask: [[Vec<bool>; N]; N]
+    #[cfg_attr(feature = "serde", serde(with = "mask_serde"))]
+    pub mask: [[Mask; N]; N]
 }
 
-use std::iter;
+/// Serde only implements arrays up to a fixed size, so masks are (de)serialized as nested
+/// sequences.
+#[cfg(feature = "serde")]
+mod mask_serde {
+    use serde::{Serialize, Serializer, Deserialize, Deserializer, de::Error};
+    use crate::Mask;
+
+    pub fn serialize<S: Serializer, const N: usize>(mask: &[[Mask; N]; N], serializer: S) -> Result<S::Ok, S::Error> {
+        let rows: Vec<&[Mask]> = mask.iter().map(|x| x.as_slice()).collect();
+        rows.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[[Mask; N]; N], D::Error> {
+        let rows: Vec<Vec<Mask>> = Vec::deserialize(deserializer)?;
+        let rows: Vec<[Mask; N]> = rows.into_iter()
+            .map(|x| x.try_into().map_err(|x: Vec<_>| D::Error::invalid_length(x.len(), &"N mask entries")))
+            .collect::<Result<_, _>>()?;
+        rows.try_into().map_err(|x: Vec<_>| D::Error::invalid_length(x.len(), &"N mask rows"))
+    }
+}
+
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
 
 impl<T, const N: usize> Tile<T,N> {
     pub fn allow_all(size:usize, additional: T) -> Tile<T,N> {
-        let mask = vec![false; size];
+        let mask = Mask::Dense(vec![false; size]);
         return Tile {
             additional,
             weight: 1,
-            mask: iter::repeat(iter::repeat(mask).take(N).collect::<Vec<_>>().try_into().unwrap()).take(N).collect::<Vec<_>>().try_into().unwrap()
+            mask: std::array::from_fn(|_| std::array::from_fn(|_| mask.clone()))
         }
     }
     pub fn disallow_all(size:usize, additional: T) -> Tile<T,N> {
-        let mask = vec![true; size];
+        let mask = Mask::Dense(vec![true; size]);
         return Tile {
             additional,
             weight: 1,
-            mask: iter::repeat(iter::repeat(mask).take(N).collect::<Vec<_>>().try_into().unwrap()).take(N).collect::<Vec<_>>().try_into().unwrap()
+            mask: std::array::from_fn(|_| std::array::from_fn(|_| mask.clone()))
+        }
+    }
+    /// Same as disallow_all, but with sparse masks that only store allowed tiles.
+    /// Use this for large pallets, and add rules with .mask[x][y].allow(id)
+    pub fn sparse(additional: T) -> Tile<T,N> {
+        return Tile {
+            additional,
+            weight: 1,
+            mask: std::array::from_fn(|_| std::array::from_fn(|_| Mask::Sparse(vec![])))
+        }
+    }
+    /// A tile with rules only at the given (dx, dy) offsets (see the neighbourhood module), every
+    /// other offset, including the center, is Mask::Any.
+    /// The rules start out disallowing everything (with sparse masks), add rules with
+    /// .mask[x][y].allow(id) or .allow_at()
+    /// Panics if an offset does not fit in N*N.
+    pub fn neighbourhood(additional: T, offsets: &[(isize, isize)]) -> Tile<T,N> {
+        let mut tile = Tile {
+            additional,
+            weight: 1,
+            mask: std::array::from_fn(|_| std::array::from_fn(|_| Mask::Any))
+        };
+        for (dx, dy) in offsets {
+            let (x, y) = Tile::<T,N>::mask_index(*dx, *dy);
+            tile.mask[x][y] = Mask::Sparse(vec![]);
+        }
+        return tile;
+    }
+    /// The mask index of an offset, panics if it does not fit in N*N.
+    pub fn mask_index(dx: isize, dy: isize) -> (usize, usize) {
+        let x = dx + (N/2) as isize;
+        let y = dy + (N/2) as isize;
+        assert!(x >= 0 && y >= 0 && x < N as isize && y < N as isize, "offset ({}, {}) does not fit in the mask", dx, dy);
+        return (x as usize, y as usize);
+    }
+    /// Allow a tile at an offset.
+    pub fn allow_at(&mut self, dx: isize, dy: isize, id: usize) {
+        let (x, y) = Tile::<T,N>::mask_index(dx, dy);
+        self.mask[x][y].allow(id);
+    }
+    /// Convert all masks to sparse masks, Mask::Any is kept as is.
+    pub fn to_sparse(&mut self, pallet_size: usize) {
+        for x in 0..N {
+            for y in 0..N {
+                if self.mask[x][y] != Mask::Any {
+                    self.mask[x][y] = self.mask[x][y].to_sparse(pallet_size);
+                }
+            }
         }
     }
+    /// Disallow a tile at every offset that has a rule, offsets with Mask::Any are left
+    /// unconstrained.
     pub fn disallow(&mut self,id: usize) {
         for x in 0..N {
             for y in 0..N {
-                self.mask[x][y][id] = true;
+                if self.mask[x][y] != Mask::Any {
+                    self.mask[x][y].disallow(id);
+                }
             }
         }
     }
-    /// Same as dissalow, but ignores diagonals
+    /// Same as dissalow, but only on the center row and column, ignoring diagonals.
     pub fn disallow_direct(&mut self,id: usize) {
         for x in 0..N {
             for y in 0..N {
-                self.mask[x][y][id] = true;
+                if (x == N/2 || y == N/2) && self.mask[x][y] != Mask::Any {
+                    self.mask[x][y].disallow(id);
+                }
             }
         }
-        self.mask[0][0][id] = false;
-        self.mask[0][N][id] = false;
-        self.mask[N][1][id] = false;
-        self.mask[N][N][id] = false;
     }
 }
 
@@ -61,8 +151,10 @@ impl<T, const N: usize> Tile<T,N> {
 /// 
 /// T: Data type for tiles.
 /// N: Size of rules. (MUST BE ODD)
+/// R: The random number generator, defaults to ChaCha12Rng.
 ///
-/// You should use the Wave::new() function to construct this to ensure you get a sane state.
+/// You should use the Wave::new() or Wave::with_rng() function to construct this to ensure you get
+/// a sane state.
 /// 
 /// The algorithm starts by assuming a state where every location is a super position of all
 /// tiles. (.wave is all trues.)
@@ -75,10 +167,20 @@ impl<T, const N: usize> Tile<T,N> {
 /// It is theoretic possible for a tile to have not possibility's, but this is very rare and not
 /// handled here.
 ///
-pub struct Wave<T: Clone, const N: usize> {
+/// Step 2 can be spread over several threads by setting .threads, see recursive_ruleset_apply.
+///
+/// Cloning a Wave copies the grid and rng, but shares the callback. Use .snapshot() to save just
+/// the state of the solver without copying the pallet.
+///
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound(deserialize = "T: Deserialize<'de>, R: Deserialize<'de>")))]
+pub struct Wave<T: Clone, const N: usize, R = ChaCha12Rng> {
     /// A callback called on each step of the .collapse() method, I used this to make an animation
     /// of the algoritim.
-    pub callback: Option<Box<dyn Fn (&Wave<T,N>, usize) -> ()>>,
+    /// Not serialized, a deserialized Wave has no callback.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub callback: Option<Callback<T,N,R>>,
     /// The pallet of tiles avalable, should not be modifyed ater creation.
     pub pallet: Vec<Tile<T,N>>,
     /// The pallet size, if this is not pallet.len(), weirdness will occur.
@@ -90,17 +192,97 @@ pub struct Wave<T: Clone, const N: usize> {
     pub x: usize,
     /// X and Y dimentions, this needs to match .wave
     pub y: usize,
-    pub rng: rand::rngs::StdRng,
+    /// The random number generator, any RngCore can be used with Wave::with_rng().
+    /// The default ChaCha12Rng is the algorithm behind rand's StdRng, but unlike StdRng its output
+    /// is stable across versions.
+    pub rng: R,
+    /// Number of threads used for propagation, 1 (the default) propagates on the calling thread.
+    /// The result is the same for any thread count.
+    pub threads: usize,
+    /// Records why possibility's were removed, see enable_trace()
+    pub trace: Option<Trace>,
+    /// Work done so far, see also .summary()
+    pub stats: Stats,
+    /// Checked by the collapse methods and during propagation, cancelling it stops the collapse
+    /// as soon as possible. Not serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cancel: Option<CancelToken>,
+}
+
+/// The type of Wave::callback
+pub type Callback<T, const N: usize, R = ChaCha12Rng> = Arc<dyn Fn (&Wave<T,N,R>, usize) + Send + Sync>;
+
+/// Result of a collapse that can stop early, see Wave::collapse_for()
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Status {
+    /// Every location is collapsed, or a contradiction.
+    Done,
+    /// Stopped before the wave was collapsed, call again to continue.
+    Paused,
+    /// Stopped because Wave::cancel was cancelled. This can happen in the middle of propagation,
+    /// so the wave should be discarded (or restored from a snapshot).
+    Cancelled,
+}
+
+/// A shared flag for stopping a collapse from another thread, see Wave::cancel
+#[derive(Debug,Clone,Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+    /// Stop every wave using this token, this can not be undone.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The state of a Wave at some point in the collapse, created by Wave::snapshot() and applied
+/// with Wave::restore().
+#[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Snapshot<R = ChaCha12Rng> {
+    /// Copy of Wave::wave
+    pub wave: Vec<Vec<Vec<bool>>>,
+    /// Copy of Wave::rng, so steps after restoring make the same choices.
+    pub rng: R,
+    /// Copy of Wave::trace
+    pub trace: Option<Trace>,
+}
+
+impl<T: Clone + fmt::Debug, const N: usize, R: fmt::Debug> fmt::Debug for Wave<T,N,R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wave")
+            .field("pallet", &self.pallet)
+            .field("pallet_size", &self.pallet_size)
+            .field("wave", &self.wave)
+            .field("x", &self.x)
+            .field("y", &self.y)
+            .field("rng", &self.rng)
+            .field("threads", &self.threads)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<T: Clone, const N: usize> Wave<T,N> {
     /// Create a solver, taking a tile pallet, size of image to generate and rng seed.
     /// Panics if x or y is zeor or the pallet is empty
     pub fn new(pallet: Vec<Tile<T,N>>, x: usize, y: usize, seed: u64) -> Wave<T,N> {
+        Wave::with_rng(pallet, x, y, ChaCha12Rng::seed_from_u64(seed))
+    }
+}
+
+impl<T: Clone, const N: usize, R: RngCore> Wave<T,N,R> {
+    /// Create a solver using a specific random number generator, otherwise the same as Wave::new().
+    pub fn with_rng(pallet: Vec<Tile<T,N>>, x: usize, y: usize, rng: R) -> Wave<T,N,R> {
         // sanity check
         assert!(x > 1);
         assert!(y > 1);
-        assert!(pallet.len() >= 1);
+        assert!(!pallet.is_empty());
         let wave = vec![vec![vec![true; pallet.len()]; y]; x];
 
         Wave {
@@ -108,12 +290,30 @@ impl<T: Clone, const N: usize> Wave<T,N> {
             x,
             y,
             pallet_size: pallet.len(),
-            pallet: pallet,
+            pallet,
             wave,
-            rng: rand::rngs::StdRng::seed_from_u64(seed)
+            rng,
+            threads: 1,
+            trace: None,
+            stats: Stats::default(),
+            cancel: None,
         }
     }
 
+    /// Check the pallet for mistakes: N must be odd, .pallet_size must match the pallet, every
+    /// mask must fit the pallet and the rules must be symmetric (if tile a allows b at (dx, dy), b
+    /// must allow a at (-dx, -dy)).
+    /// Returns every problem found.
+    pub fn validate(&self) -> Result<(), Vec<RuleError>> {
+        let errors = validate(&self.pallet, self.pallet_size);
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    /// Find tiles that cause contradictions or are redundant, see Diagnostics
+    pub fn diagnose(&self) -> Diagnostics {
+        diagnose(&self.pallet)
+    }
+
     /// Get the entropy of a tile, returns f32::MAX for colapsed tiles, and contradictions
     // TODO take weight into account
     fn get_entropy(&self, x: usize, y: usize) -> f32 {
@@ -152,56 +352,71 @@ impl<T: Clone, const N: usize> Wave<T,N> {
     /// Update the wavefunction of surrounding nodes
     /// This repatedy applys rules to reduce the enthropy as much as possible, and prevent
     /// contradictions.
+    ///
+    /// Propagation always ends in the same state (the largest set of possibility's consistent with
+    /// the rules), so the order tiles are processed in does not change the result. This lets
+    /// propagation be split over .threads threads without losing determinism.
+    ///
+    /// When tracing, propagation always runs on the calling thread.
     fn recursive_ruleset_apply(&mut self, x: usize, y:usize) {
+        let start = Instant::now();
+        if self.threads > 1 && self.trace.is_none() {
+            self.parallel_ruleset_apply(x, y);
+        } else {
+            self.serial_ruleset_apply(x, y);
+        }
+        self.stats.propagation_time += start.elapsed();
+    }
+
+    /// Single threaded version of recursive_ruleset_apply
+    fn serial_ruleset_apply(&mut self, x: usize, y:usize) {
         let mut stack = vec![(x,y)];
         let combined_mask = vec![true; self.pallet_size];
-        let mut combined_mask: [[Vec<_>;N];N] = iter::repeat(iter::repeat(combined_mask).take(N).collect::<Vec<_>>().try_into().unwrap()).take(N).collect::<Vec<_>>().try_into().unwrap();
+        let mut combined_mask: [[Vec<_>;N];N] = std::array::from_fn(|_| std::array::from_fn(|_| combined_mask.clone()));
+        let offsets = self.constrained_offsets();
         
-        while stack.len() > 0 {
+        while let Some((x,y)) = stack.pop() {
             //println!("Stack size {} ", stack.len());
-            let (x,y) = stack.pop().unwrap();
+            if self.is_cancelled() {
+                return;
+            }
+            self.stats.pops += 1;
             // Find all allowed rulesets for current tile
             let allowed_idxs = self.wave[x][y].iter().enumerate().filter(|(_idx, v)| **v);
-            let allowed_masks = allowed_idxs.map(|(idx, _v)| self.pallet[idx].mask.clone());
+            let allowed_masks = allowed_idxs.map(|(idx, _v)| &self.pallet[idx].mask);
             // Initalizie all "true" mask.
-            for i in 0..N {
-                for e in 0..N {
-                    for idx in 0..self.pallet_size {
-                        combined_mask[i][e][idx] = true;
-                    }
+            for (i, e) in &offsets {
+                for idx in 0..self.pallet_size {
+                    combined_mask[*i][*e][idx] = true;
                 }
             }
             // Combine all masks with and.
             for mask in allowed_masks {
-                for x in 0..N {
-                    for y in 0..N {
-                        for idx in 0..self.pallet_size {
-                            combined_mask[x][y][idx] &= mask[x][y][idx]
-                        }
-                    }
+                for (x, y) in &offsets {
+                    mask[*x][*y].unban(&mut combined_mask[*x][*y]);
                 }
             }
             // Apply combined mask
-            for mask_x in 0..N {
-                for mask_y in 0..N {
-                    let offset_x = mask_x as isize - (N/2) as isize;
-                    let offset_y = mask_y as isize - (N/2) as isize;
-                    let wave_x = x as isize + offset_x;
-                    let wave_y = y as isize + offset_y;
-                    if wave_x >= 0 && wave_x < self.x as isize && wave_y >= 0 && wave_y < self.y as isize {
-                        for id in 0..self.pallet_size {
-                            let mut append_stack = false;
-                            if combined_mask[mask_x][mask_y][id] {
-                                if self.wave[wave_x as usize][wave_y as usize][id] {
-                                    append_stack = true
-                                }
-                                self.wave[wave_x as usize][wave_y as usize][id] = false
-                            }
-                            if append_stack {
-                                if !stack.contains(&(wave_x as usize, wave_y as usize)) {
-                                    stack.push((wave_x as usize,wave_y as usize));
+            for &(mask_x, mask_y) in &offsets {
+                let offset_x = mask_x as isize - (N/2) as isize;
+                let offset_y = mask_y as isize - (N/2) as isize;
+                let wave_x = x as isize + offset_x;
+                let wave_y = y as isize + offset_y;
+                if wave_x >= 0 && wave_x < self.x as isize && wave_y >= 0 && wave_y < self.y as isize {
+                    for id in 0..self.pallet_size {
+                        let mut append_stack = false;
+                        if combined_mask[mask_x][mask_y][id] {
+                            if self.wave[wave_x as usize][wave_y as usize][id] {
+                                append_stack = true;
+                                self.stats.bans += 1;
+                                if let Some(trace) = &mut self.trace {
+                                    trace.remove(wave_x as usize, wave_y as usize, id, Cause::Banned { from: (x, y), dx: offset_x, dy: offset_y });
                                 }
                             }
+                            self.wave[wave_x as usize][wave_y as usize][id] = false
+                        }
+                        if append_stack && !stack.contains(&(wave_x as usize, wave_y as usize)) {
+                            stack.push((wave_x as usize,wave_y as usize));
                         }
                     }
                 }
@@ -210,26 +425,122 @@ impl<T: Clone, const N: usize> Wave<T,N> {
         }
     }
 
+    /// Mask positions where at least one tile has a rule, offsets where every mask is Mask::Any
+    /// never remove anything and are skipped by propagation.
+    fn constrained_offsets(&self) -> Vec<(usize, usize)> {
+        let mut offsets = vec![];
+        for x in 0..N {
+            for y in 0..N {
+                if self.pallet.iter().any(|tile| tile.mask[x][y] != Mask::Any) {
+                    offsets.push((x, y));
+                }
+            }
+        }
+        return offsets;
+    }
+
+    /// Multithreaded version of recursive_ruleset_apply.
+    ///
+    /// Works in rounds: the possibility's of every changed tile are copied, then the grid is split
+    /// into .threads vertical stripes and each thread applies the rules of all changed tiles to
+    /// its own stripe. Tiles that lost a possibility form the next round.
+    fn parallel_ruleset_apply(&mut self, x: usize, y: usize) {
+        let stripe_width = self.x.div_ceil(self.threads);
+        let (size_y, pallet_size) = (self.y, self.pallet_size);
+        // Only the masks are shared with the workers, so T does not need to be Sync.
+        let masks: Vec<&[[Mask; N]; N]> = self.pallet.iter().map(|tile| &tile.mask).collect();
+        let offsets = self.constrained_offsets();
+        let mut changed = vec![(x, y)];
+
+        while !changed.is_empty() && !self.is_cancelled() {
+            let sources: Vec<(usize, usize, Vec<usize>)> = changed.iter().map(|&(x, y)| {
+                let allowed = self.wave[x][y].iter().enumerate().filter(|(_idx, v)| **v).map(|(idx, _v)| idx).collect();
+                (x, y, allowed)
+            }).collect();
+            let sources = &sources;
+            let masks = &masks;
+            let offsets = &offsets;
+            self.stats.pops += sources.len();
+
+            let bans;
+            (changed, bans) = thread::scope(|scope| {
+                let workers: Vec<_> = self.wave.chunks_mut(stripe_width).enumerate().map(|(stripe, columns)| {
+                    scope.spawn(move || {
+                        let first_x = (stripe * stripe_width) as isize;
+                        let last_x = first_x + columns.len() as isize;
+                        let mut changed = vec![];
+                        let mut bans = 0;
+                        let mut seen = HashSet::new();
+                        let mut banned = vec![true; pallet_size];
+                        for (x, y, allowed) in sources {
+                            for &(mask_x, mask_y) in offsets {
+                                let wave_x = *x as isize + mask_x as isize - (N/2) as isize;
+                                if wave_x < first_x || wave_x >= last_x {
+                                    continue;
+                                }
+                                let wave_y = *y as isize + mask_y as isize - (N/2) as isize;
+                                if wave_y < 0 || wave_y >= size_y as isize {
+                                    continue;
+                                }
+                                // A possibility is removed if every allowed tile disallows it.
+                                banned.fill(true);
+                                for idx in allowed {
+                                    masks[*idx][mask_x][mask_y].unban(&mut banned);
+                                }
+                                let superposition = &mut columns[(wave_x - first_x) as usize][wave_y as usize];
+                                let mut removed = false;
+                                for id in 0..pallet_size {
+                                    if superposition[id] && banned[id] {
+                                        superposition[id] = false;
+                                        removed = true;
+                                        bans += 1;
+                                    }
+                                }
+                                let location = (wave_x as usize, wave_y as usize);
+                                if removed && seen.insert(location) {
+                                    changed.push(location);
+                                }
+                            }
+                        }
+                        (changed, bans)
+                    })
+                }).collect();
+                let mut changed = vec![];
+                let mut bans = 0;
+                for worker in workers {
+                    let (worker_changed, worker_bans) = worker.join().unwrap();
+                    changed.extend(worker_changed);
+                    bans += worker_bans;
+                }
+                (changed, bans)
+            });
+            self.stats.bans += bans;
+        }
+    }
+
     /// Single step the wave-function-collapse algoritim
     /// Returns x, y, and collapsed idx of the tile
     pub fn step(&mut self) -> (usize, usize, usize) {
+        let start = Instant::now();
+        self.stats.steps += 1;
         let (best_x, best_y) = self.get_lowest_entropy();
 
         let superposition = &self.wave[best_x][best_y];
     
         let mut allowed = vec![];
         let mut weights = vec![];
-        let mut total_allowed_weights = 0;
+        // Summed as u64, so the weights of many tiles can not overflow
+        let mut total_allowed_weights: u64 = 0;
 
         for (idx, bit) in superposition.iter().enumerate() {
             if *bit {
-                total_allowed_weights += self.pallet[idx].weight;
+                total_allowed_weights += self.pallet[idx].weight as u64;
                 allowed.push(idx);
-                weights.push(self.pallet[idx].weight) 
+                weights.push(self.pallet[idx].weight as u64) 
             }
         }
       
-        let rng = self.rng.next_u32();
+        let rng = self.rng.next_u64();
 
         // weighted selection
         let rng = rng % total_allowed_weights;
@@ -252,13 +563,40 @@ impl<T: Clone, const N: usize> Wave<T,N> {
 
         new_position[selection] = true;
 
+        if let Some(trace) = &mut self.trace {
+            trace.observe(best_x, best_y, &self.wave[best_x][best_y], selection);
+        }
+
         self.wave[best_x][best_y] = new_position;
+        self.stats.selection_time += start.elapsed();
 
         self.recursive_ruleset_apply(best_x, best_y);
 
         return (best_x, best_y, selection)
     }
 
+    /// Remove every tile not in allowed from a location, and propagate the change.
+    /// Used to constrain the output before collapsing, for example to force tiles along an edge.
+    pub fn restrict(&mut self, x: usize, y: usize, allowed: &[usize]) {
+        let mut keep = vec![false; self.pallet_size];
+        for id in allowed {
+            keep[*id] = true;
+        }
+        let mut removed = false;
+        for id in 0..self.pallet_size {
+            if self.wave[x][y][id] && !keep[id] {
+                self.wave[x][y][id] = false;
+                removed = true;
+                if let Some(trace) = &mut self.trace {
+                    trace.remove(x, y, id, Cause::Restricted);
+                }
+            }
+        }
+        if removed {
+            self.recursive_ruleset_apply(x, y);
+        }
+    }
+
     /// Checks if the wave function is fully collapsed, returns true on contradiction.
     pub fn is_done(&self) -> bool {
         for x in 0..self.x {
@@ -297,21 +635,149 @@ impl<T: Clone, const N: usize> Wave<T,N> {
         return false;
     }
     
+    /// Checks if .cancel is set and cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|x| x.is_cancelled())
+    }
+
     /// Fully collapse a wavefunction, may produce a function with contradictions.
     /// Returns the count of steps it took to collapse.
+    /// If .cancel is cancelled this returns early, leaving the wave partly collapsed, and the count
+    /// only covers the steps taken before that (0 if it was already cancelled). The count does not
+    /// say if the wave finished, check .is_cancelled() or use .collapse_for(usize::MAX), which
+    /// returns Status::Cancelled.
     pub fn collapse(&mut self) -> usize {
         let mut count = 0;
-        while !self.is_done() {
+        while !self.is_done() && !self.is_cancelled() {
             self.step();
             count += 1;
-            match &self.callback {
-                Some(n) => n(self, count),
-                None => ()
+            if let Some(n) = &self.callback {
+                n(self, count)
             }
         }
         return count;
     }
 
+    /// Collapse at most max_steps locations, and stop early.
+    /// The wave is left fully propagated, so calling this (or .collapse()) again continues where it
+    /// stopped.
+    pub fn collapse_for(&mut self, max_steps: usize) -> Status {
+        let mut count = 0;
+        while !self.is_done() {
+            if self.is_cancelled() {
+                return Status::Cancelled;
+            }
+            if count >= max_steps {
+                return Status::Paused;
+            }
+            self.step();
+            count += 1;
+            if let Some(n) = &self.callback {
+                n(self, count)
+            }
+        }
+        if self.is_cancelled() {
+            return Status::Cancelled;
+        }
+        return Status::Done;
+    }
+
+    /// Collapse until the deadline passes, like .collapse_for()
+    /// The deadline is checked between steps, so a slow step can overrun it.
+    pub fn collapse_until(&mut self, deadline: Instant) -> Status {
+        let mut count = 0;
+        while !self.is_done() {
+            if self.is_cancelled() {
+                return Status::Cancelled;
+            }
+            if Instant::now() >= deadline {
+                return Status::Paused;
+            }
+            self.step();
+            count += 1;
+            if let Some(n) = &self.callback {
+                n(self, count)
+            }
+        }
+        if self.is_cancelled() {
+            return Status::Cancelled;
+        }
+        return Status::Done;
+    }
+
+    /// Save the grid and rng state, the pallet is not copied.
+    pub fn snapshot(&self) -> Snapshot<R> where R: Clone {
+        Snapshot {
+            wave: self.wave.clone(),
+            rng: self.rng.clone(),
+            trace: self.trace.clone(),
+        }
+    }
+
+    /// Return to the state saved by .snapshot(), can be used any number of times.
+    /// Panics if the snapshot was taken from a wave with a different size or pallet size.
+    pub fn restore(&mut self, snapshot: &Snapshot<R>) where R: Clone {
+        assert_eq!(snapshot.wave.len(), self.x);
+        assert!(snapshot.wave.iter().flatten().all(|x| x.len() == self.pallet_size));
+        assert!(snapshot.wave.iter().all(|x| x.len() == self.y));
+        self.wave.clone_from(&snapshot.wave);
+        self.rng.clone_from(&snapshot.rng);
+        self.trace.clone_from(&snapshot.trace);
+    }
+
+    /// Start recording why each possibility is removed, so contradictions can be explained with
+    /// .explain_contradiction(). Removals before this are not recorded.
+    pub fn enable_trace(&mut self) {
+        self.trace = Some(Trace::new(self.x, self.y, self.pallet_size));
+    }
+
+    /// Explain the first location that ran out of possibility's: returns the chain of
+    /// observations and bans that led to it (see Trace::explain()), or None if there is no
+    /// contradiction or tracing is not enabled.
+    pub fn explain_contradiction(&self) -> Option<Vec<Removal>> {
+        let trace = self.trace.as_ref()?;
+        let (x, y) = trace.first_contradiction(&self.wave)?;
+        Some(trace.explain(x, y))
+    }
+
+    /// Number of locations with a single possibility left.
+    pub fn collapsed_count(&self) -> usize {
+        self.wave.iter().flatten().filter(|x| x.iter().filter(|x| **x).count() == 1).count()
+    }
+
+    /// Shannon entropy (in nats) of a location, using the weights of the remaining tiles.
+    /// 0 for collapsed locations and contradictions.
+    pub fn shannon_entropy(&self, x: usize, y: usize) -> f64 {
+        let weights = self.wave[x][y].iter().enumerate().filter(|(_idx, v)| **v).map(|(idx, _v)| self.pallet[idx].weight as f64);
+        let (sum, sum_log) = weights.filter(|w| *w > 0.0).fold((0.0, 0.0), |(sum, sum_log), w| (sum + w, sum_log + w * w.ln()));
+        if sum == 0.0 {
+            return 0.0;
+        }
+        return (sum.ln() - sum_log / sum).max(0.0);
+    }
+
+    /// Sum of the Shannon entropy of all locations, goes to 0 as the wave collapses.
+    pub fn total_entropy(&self) -> f64 {
+        let mut total = 0.0;
+        for x in 0..self.x {
+            for y in 0..self.y {
+                total += self.shannon_entropy(x, y);
+            }
+        }
+        return total;
+    }
+
+    /// Progress of the collapse and the statistics so far.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            cells: self.x * self.y,
+            collapsed: self.collapsed_count(),
+            contradictions: self.wave.iter().flatten().filter(|x| !x.contains(&true)).count(),
+            entropy: self.total_entropy(),
+            stats: self.stats.clone(),
+        }
+    }
+
     /// Gets the tileid for a collapsed location in the wavefunction. None if it is not col;apsed.
     pub fn get_collapsed_tile(&self, x: usize, y: usize) -> Option<usize> {
         let superposition = &self.wave[x][y];
@@ -358,12 +824,70 @@ impl<T: Clone, const N: usize> Wave<T,N> {
         }
         Some(buf) 
     }
+
+    /// Gets the tileids still possible at a location, empty for a contradiction.
+    pub fn get_possibilities(&self, x: usize, y: usize) -> Vec<usize> {
+        self.wave[x][y].iter().enumerate().filter(|(_idx, v)| **v).map(|(idx, _v)| idx).collect()
+    }
+
+    /// Returns a 2dim vector of the tileids still possible at every location, works at any point
+    /// of the collapse.
+    pub fn get_partial_vec(&self) -> Vec<Vec<Vec<usize>>> {
+        (0..self.x).map(|x| (0..self.y).map(|y| self.get_possibilities(x, y)).collect()).collect()
+    }
+
+    /// Returns the assocated data for every collapsed location, and None for the rest.
+    pub fn get_partial_data(&self) -> Vec<Vec<Option<&T>>> {
+        (0..self.x).map(|x| (0..self.y).map(|y| {
+            self.get_collapsed_tile(x, y).map(|idx| &self.pallet[idx].additional)
+        }).collect()).collect()
+    }
+
+    /// Returns the probability of every tile at every location, from the weights of the tiles that
+    /// are still possible. The result is a flat x * y * pallet_size tensor in [x][y][tile] order,
+    /// so the probability of a tile is at (x * .y + y) * .pallet_size + tile.
+    /// Each location sums to 1, except contradictions which are all 0. If all remaining tiles have
+    /// weight 0 they are treated as equally likely.
+    pub fn get_probabilities(&self) -> Vec<f32> {
+        let mut probabilities = vec![0.0; self.x * self.y * self.pallet_size];
+        for (location, superposition) in self.wave.iter().flatten().enumerate() {
+            let weights: Vec<f64> = (0..self.pallet_size)
+                .map(|idx| if superposition[idx] { self.pallet[idx].weight as f64 } else { 0.0 })
+                .collect();
+            let total: f64 = weights.iter().sum();
+            let count = superposition.iter().filter(|x| **x).count() as f64;
+            let out = &mut probabilities[location * self.pallet_size..(location + 1) * self.pallet_size];
+            for idx in 0..self.pallet_size {
+                out[idx] = if total > 0.0 {
+                    (weights[idx] / total) as f32
+                } else if superposition[idx] {
+                    (1.0 / count) as f32
+                } else {
+                    0.0
+                };
+            }
+        }
+        return probabilities;
+    }
+
+    /// Returns the weighted average (see Blend) of the data of the possible tiles at every
+    /// location, None for contradictions. Collapsed locations get their tile's data.
+    /// This gives the blurry preview of a partly collapsed wave.
+    pub fn get_preview(&self) -> Vec<Vec<Option<T>>> where T: Blend {
+        (0..self.x).map(|x| (0..self.y).map(|y| {
+            let items: Vec<_> = self.get_possibilities(x, y).into_iter()
+                .map(|idx| (&self.pallet[idx].additional, self.pallet[idx].weight))
+                .collect();
+            if items.is_empty() { None } else { Some(T::blend(&items)) }
+        }).collect()).collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Tile;
     use super::Wave;
+    #[test]
     fn get_lowest_entropy() {
         let pallet = vec![Tile::<u32, 3>::allow_all(3, 0), Tile::allow_all(3, 0), Tile::allow_all(3, 0)];
         let mut wave = Wave::new(pallet, 3, 3, 123);
@@ -380,10 +904,203 @@ mod tests {
     fn full_collapse() {
         let pallet = vec![Tile::<u32, 3>::allow_all(2, 0), Tile::allow_all(2, 0)];
         let mut wave = Wave::new(pallet, 3, 3, 123);
-        wave.colapse();
+        wave.collapse();
         println!("{:?}", wave);
         assert!(wave.is_done());
         assert!(!wave.is_contradiction());
     }
+    #[test]
+    fn parallel_propagation() {
+        // Tiles may not touch themselves, except at the center.
+        let mut pallet = vec![];
+        for id in 0..5 {
+            let mut tile = Tile::<u32, 3>::allow_all(5, id);
+            tile.disallow(id as usize);
+            tile.mask[1][1].allow(id as usize);
+            pallet.push(tile);
+        }
+        let mut single = Wave::new(pallet.clone(), 20, 13, 42);
+        single.collapse();
+        let mut parallel = Wave::new(pallet, 20, 13, 42);
+        parallel.threads = 3;
+        parallel.collapse();
+        assert_eq!(single.wave, parallel.wave);
+        assert_eq!(single.stats.steps, parallel.stats.steps);
+    }
+    #[test]
+    fn plus_neighbourhood() {
+        // A checkerboard, tiles must differ from their 4 direct neighbours. Diagonals are free.
+        let mut pallet = vec![];
+        for id in 0..2 {
+            let mut tile = Tile::<u32, 3>::neighbourhood(id as u32, &crate::neighbourhood::plus());
+            for (dx, dy) in crate::neighbourhood::plus() {
+                tile.allow_at(dx, dy, 1 - id);
+            }
+            pallet.push(tile);
+        }
+        assert_eq!(pallet[0].mask[0][0], crate::Mask::Any);
+        let mut wave = Wave::new(pallet, 6, 5, 3);
+        assert_eq!(wave.validate(), Ok(()));
+        wave.collapse();
+        assert!(!wave.is_contradiction());
+        let tiles = wave.get_collapsed_vec().unwrap();
+        for x in 0..6 {
+            for y in 0..5 {
+                assert_eq!(tiles[x][y], (x + y + tiles[0][0]) % 2);
+            }
+        }
+    }
+    #[test]
+    fn budgeted_collapse() {
+        use super::Status;
+        use std::time::{Duration, Instant};
+        let pallet: Vec<_> = (0..4).map(|id| {
+            let mut tile = Tile::<u32, 3>::allow_all(4, id);
+            tile.disallow(id as usize);
+            tile.mask[1][1].allow(id as usize);
+            tile
+        }).collect();
+        let mut full = Wave::new(pallet.clone(), 7, 5, 11);
+        full.collapse();
+        let mut wave = Wave::new(pallet, 7, 5, 11);
+        assert_eq!(wave.collapse_until(Instant::now() - Duration::from_secs(1)), Status::Paused);
+        assert_eq!(wave.stats.steps, 0);
+        while wave.collapse_for(3) == Status::Paused {
+            assert!(wave.stats.steps % 3 == 0);
+        }
+        assert_eq!(wave.wave, full.wave);
+        assert_eq!(wave.collapse_until(Instant::now()), Status::Done);
+    }
+    #[test]
+    fn cancel() {
+        use super::{CancelToken, Status};
+        let pallet: Vec<_> = (0..3).map(|id| Tile::<u32, 3>::allow_all(3, id)).collect();
+        let mut wave = Wave::new(pallet, 10, 10, 0);
+        let token = CancelToken::new();
+        wave.cancel = Some(token.clone());
+        // Cancel from the callback after 5 steps.
+        wave.callback = Some(std::sync::Arc::new(move |_wave, count| if count == 5 { token.cancel() }));
+        assert_eq!(wave.collapse_for(100), Status::Cancelled);
+        assert_eq!(wave.stats.steps, 5);
+        assert_eq!(wave.collapse(), 0);
+        assert!(wave.is_cancelled() && !wave.is_done());
+
+        // Cancelled in the middle of .collapse()
+        let pallet: Vec<_> = (0..3).map(|id| Tile::<u32, 3>::allow_all(3, id)).collect();
+        let mut wave = Wave::new(pallet, 10, 10, 0);
+        let token = CancelToken::new();
+        wave.cancel = Some(token.clone());
+        wave.callback = Some(std::sync::Arc::new(move |_wave, count| if count == 7 { token.cancel() }));
+        assert_eq!(wave.collapse(), 7);
+        assert!(wave.is_cancelled() && !wave.is_done());
+    }
+    #[test]
+    fn large_weights() {
+        let mut pallet = vec![Tile::<u8, 3>::allow_all(3, 0), Tile::allow_all(3, 1), Tile::allow_all(3, 2)];
+        for tile in &mut pallet {
+            tile.weight = u32::MAX;
+        }
+        let mut wave = Wave::new(pallet, 4, 4, 0);
+        wave.collapse();
+        assert!(wave.is_done() && !wave.is_contradiction());
+        let data = wave.get_collapsed_data().unwrap();
+        assert!(data.iter().flatten().any(|x| **x != 0));
+    }
+    #[test]
+    fn partial_results() {
+        let mut pallet = vec![Tile::<u8, 3>::allow_all(2, 0), Tile::allow_all(2, 100)];
+        pallet[1].weight = 3;
+        let mut wave = Wave::new(pallet, 2, 2, 0);
+        wave.wave[0][1][0] = false;
+        wave.wave[1][1] = vec![false, false];
+        assert_eq!(wave.get_partial_vec(), vec![vec![vec![0, 1], vec![1]], vec![vec![0, 1], vec![]]]);
+        assert_eq!(wave.get_partial_data(), vec![vec![None, Some(&100)], vec![None, None]]);
+        assert_eq!(wave.get_preview(), vec![vec![Some(75), Some(100)], vec![Some(75), None]]);
+        assert_eq!(wave.get_collapsed_data(), None);
+        assert_eq!(wave.get_probabilities(), vec![0.25, 0.75, 0.0, 1.0, 0.25, 0.75, 0.0, 0.0]);
+    }
+    #[test]
+    fn summary() {
+        let pallet = vec![Tile::<u32, 3>::allow_all(2, 0), Tile::allow_all(2, 0)];
+        let mut wave = Wave::new(pallet, 3, 4, 5);
+        let start = wave.summary();
+        assert_eq!(start.cells, 12);
+        assert_eq!(start.collapsed, 0);
+        assert!((start.entropy - 12.0 * 2f64.ln()).abs() < 1e-9);
+        let steps = wave.collapse();
+        let end = wave.summary();
+        assert_eq!(end.collapsed, 12);
+        assert_eq!(end.contradictions, 0);
+        assert_eq!(end.entropy, 0.0);
+        assert_eq!(end.stats.steps, steps);
+        assert_eq!(end.stats.bans, 0);
+        assert_eq!(end.stats.pops, steps);
+    }
+    #[test]
+    fn snapshot_restore() {
+        let mut pallet = vec![];
+        for id in 0..4 {
+            let mut tile = Tile::<u32, 3>::allow_all(4, id);
+            tile.disallow(id as usize);
+            tile.mask[1][1].allow(id as usize);
+            pallet.push(tile);
+        }
+        let mut wave = Wave::new(pallet, 6, 6, 7);
+        wave.step();
+        let snapshot = wave.snapshot();
+        let mut fork = wave.clone();
+        wave.collapse();
+        let first = wave.wave.clone();
+        wave.restore(&snapshot);
+        assert_eq!(wave.wave, snapshot.wave);
+        wave.collapse();
+        fork.collapse();
+        assert_eq!(wave.wave, first);
+        assert_eq!(fork.wave, first);
+    }
+    #[test]
+    fn custom_rng() {
+        let pallet: Vec<_> = (0..3).map(|id| Tile::<u32, 3>::allow_all(3, id)).collect();
+        let mut wave = Wave::with_rng(pallet, 4, 4, rand::rngs::mock::StepRng::new(0, 0));
+        wave.collapse();
+        // An rng that always returns 0 picks the first possibility every step.
+        assert_eq!(wave.get_collapsed_vec(), Some(vec![vec![0; 4]; 4]));
+    }
+    #[test]
+    fn explain_contradiction() {
+        use crate::Cause;
+        // Tile 0 can not have anything to its right, tile 1 fits anywhere.
+        let mut pallet = vec![Tile::<u32, 3>::allow_all(2, 0), Tile::allow_all(2, 1)];
+        pallet[0].mask[2][1] = crate::Mask::Dense(vec![true, true]);
+        let mut wave = Wave::new(pallet, 3, 3, 1);
+        wave.enable_trace();
+        assert_eq!(wave.explain_contradiction(), None);
+        // Force tile 0 in the middle
+        wave.wave[1][1][1] = false;
+        wave.recursive_ruleset_apply(1, 1);
+        let chain = wave.explain_contradiction().unwrap();
+        let last = chain.last().unwrap();
+        assert_eq!((last.x, last.y), (2, 1));
+        assert_eq!(last.cause, Cause::Banned { from: (1, 1), dx: 1, dy: 0 });
+        assert_eq!(chain.len(), 2);
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_resume() {
+        let pallet: Vec<_> = (0..3).map(|id| {
+            let mut tile = Tile::<u32, 3>::allow_all(3, id);
+            tile.disallow(id as usize);
+            tile.mask[1][1].allow(id as usize);
+            tile
+        }).collect();
+        let mut wave = Wave::new(pallet, 5, 4, 99);
+        wave.step();
+        let saved = serde_json::to_string(&wave).unwrap();
+        wave.collapse();
+        let mut resumed: Wave<u32, 3> = serde_json::from_str(&saved).unwrap();
+        resumed.collapse();
+        assert_eq!(resumed.wave, wave.wave);
+        assert_eq!(resumed.pallet[2].mask, wave.pallet[2].mask);
+    }
 
 }